@@ -0,0 +1,68 @@
+use crate::config;
+use crate::error_manager::ManagerError;
+use log::info;
+use std::fs;
+use std::path::PathBuf;
+
+// Storage for inbound media bytes, returns a reference downstream systems can use to get the file
+pub trait BlobStore {
+    fn store(&self, key: &str, data: &[u8]) -> Result<String, ManagerError>;
+}
+
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub fn new(root: PathBuf) -> LocalBlobStore {
+        LocalBlobStore { root }
+    }
+}
+
+pub fn is_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-'
+}
+
+// Keys are relative paths, a segment can't leave the storage root
+fn validate_key(key: &str) -> Result<(), ManagerError> {
+    for segment in key.split('/') {
+        let dots = segment == "." || segment == "..";
+
+        if segment.is_empty() || dots || !segment.chars().all(is_key_char) {
+            return Err(ManagerError::Validation(format!(
+                "Storage key {} is not valid",
+                key
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+impl BlobStore for LocalBlobStore {
+    fn store(&self, key: &str, data: &[u8]) -> Result<String, ManagerError> {
+        validate_key(key)?;
+        let path = self.root.join(key);
+
+        let stored = match path.parent() {
+            Some(parent) => fs::create_dir_all(parent).and_then(|_| fs::write(&path, data)),
+            None => fs::write(&path, data),
+        };
+        if let Err(err) = stored {
+            return Err(ManagerError::Internal(format!(
+                "Couldnt store media on {}: {}",
+                path.display(),
+                err
+            )));
+        }
+
+        info!("Stored {} bytes on {}", data.len(), path.display());
+        Ok(format!("file://{}", path.display()))
+    }
+}
+
+pub fn default_store() -> Box<dyn BlobStore> {
+    let root = &config::get().media_storage_path;
+
+    Box::new(LocalBlobStore::new(PathBuf::from(root)))
+}
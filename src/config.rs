@@ -0,0 +1,473 @@
+use log::error;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+pub const CONFIG_FILE_VAR: &str = "CONFIG_FILE";
+
+// Loaded once from an optional TOML file (CONFIG_FILE), environment variables take precedence
+pub struct Config {
+    pub graph_base_url: String,
+    pub graph_api_version: String,
+    // Default business number, used by systems without a sender
+    pub phone_number_id: String,
+    pub senders: Vec<Sender>,
+    pub meta_token: String,
+    // Verifies the signature of webhook events
+    pub app_secret: String,
+    // Expected by the webhook subscription
+    pub verify_token: String,
+    // Only needed to manage templates
+    pub business_account_id: Option<String>,
+    pub redis_url: String,
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    pub media_storage_path: String,
+    // Header of interactive messages when the request doesn't set one, by system id
+    pub default_header: String,
+    pub default_headers: HashMap<u8, String>,
+}
+
+// Business number messages can be sent from, by name or phone number id
+pub struct Sender {
+    pub name: String,
+    pub phone_number_id: String,
+    // Systems that send from this number when the request doesn't choose one
+    pub systems: Vec<u8>,
+}
+
+impl Config {
+    // Base url of the graph api including its version, like https://graph.facebook.com/v15.0
+    pub fn graph_url(&self) -> String {
+        format!(
+            "{}/{}",
+            self.graph_base_url.trim_end_matches('/'),
+            self.graph_api_version
+        )
+    }
+
+    pub fn authorization(&self) -> String {
+        format!("Bearer {}", self.meta_token)
+    }
+
+    // Phone number id to send from, the requested sender or the one of the system
+    pub fn sender(&self, requested: Option<&str>, system_id: u8) -> Result<&str, String> {
+        match requested {
+            Some(requested) => self
+                .senders
+                .iter()
+                .find(|sender| sender.name == requested || sender.phone_number_id == requested)
+                .map(|sender| sender.phone_number_id.as_str())
+                .ok_or(format!("Sender {} is not registered", requested)),
+            None => Ok(self
+                .senders
+                .iter()
+                .find(|sender| sender.systems.contains(&system_id))
+                .map_or(self.phone_number_id.as_str(), |sender| {
+                    sender.phone_number_id.as_str()
+                })),
+        }
+    }
+
+    pub fn is_sender(&self, phone_number_id: &str) -> bool {
+        self.senders
+            .iter()
+            .any(|sender| sender.phone_number_id == phone_number_id)
+    }
+
+    pub fn default_header(&self, system_id: u8) -> String {
+        self.default_headers
+            .get(&system_id)
+            .unwrap_or(&self.default_header)
+            .clone()
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct FileConfig {
+    graph_base_url: Option<String>,
+    graph_api_version: Option<String>,
+    phone_number_id: Option<String>,
+    meta_token: Option<String>,
+    app_secret: Option<String>,
+    verify_token: Option<String>,
+    business_account_id: Option<String>,
+    redis_url: Option<String>,
+    connect_timeout_secs: Option<u64>,
+    request_timeout_secs: Option<u64>,
+    media_storage_path: Option<String>,
+    default_header: Option<String>,
+    // Keyed by system id, like "2" = "Pescara Auto"
+    default_headers: HashMap<String, String>,
+    // Keyed by sender name
+    senders: HashMap<String, FileSender>,
+}
+
+#[derive(Deserialize)]
+struct FileSender {
+    phone_number_id: String,
+    #[serde(default)]
+    systems: Vec<u8>,
+}
+
+// Every problem found, so they can be fixed at once instead of one per boot
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration: {}", self.0.join(", "))
+    }
+}
+
+impl Error for ConfigError {}
+
+// Loads and validates the configuration, call it on boot before anything uses get()
+pub fn init() -> Result<&'static Config, ConfigError> {
+    let config = load()?;
+
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(|| match load() {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}", err);
+            panic!("{}", err)
+        }
+    })
+}
+
+fn load() -> Result<Config, ConfigError> {
+    let vars: HashMap<String, String> = std::env::vars().collect();
+
+    let file = match vars.get(CONFIG_FILE_VAR) {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(content) => match toml::from_str::<FileConfig>(&content) {
+                Ok(file) => file,
+                Err(err) => {
+                    return Err(ConfigError(vec![format!("{} is not valid: {}", path, err)]))
+                }
+            },
+            Err(err) => return Err(ConfigError(vec![format!("Couldnt read {}: {}", path, err)])),
+        },
+        None => FileConfig::default(),
+    };
+
+    merge(&vars, file)
+}
+
+// Builds the configuration from the environment variables and the file
+fn merge(vars: &HashMap<String, String>, file: FileConfig) -> Result<Config, ConfigError> {
+    let mut problems = vec![];
+    // An empty variable is taken as unset, so it doesn't shadow the file
+    let env = |name: &str| vars.get(name).filter(|value| !value.is_empty()).cloned();
+
+    let mut required = |var: &str, key: &str, value: Option<String>| -> String {
+        match env(var).or(value).filter(|value| !value.is_empty()) {
+            Some(value) => value,
+            None => {
+                problems.push(format!(
+                    "{} (or {} in the config file) is missing",
+                    var, key
+                ));
+                "".to_string()
+            }
+        }
+    };
+
+    let phone_number_id = required("PHONE_NUMBER_ID", "phone_number_id", file.phone_number_id);
+    let meta_token = required("META_TOKEN", "meta_token", file.meta_token);
+    let app_secret = required("APP_SECRET", "app_secret", file.app_secret);
+    let verify_token = required("VERIFY_TOKEN", "verify_token", file.verify_token);
+    let redis_url = required("REDIS_URL", "redis_url", file.redis_url);
+
+    let mut seconds = |var: &str, value: Option<u64>, default: u64| -> Duration {
+        match env(var) {
+            Some(value) => match value.parse() {
+                Ok(seconds) => Duration::from_secs(seconds),
+                Err(_) => {
+                    problems.push(format!(
+                        "{} must be a number of seconds, found {}",
+                        var, value
+                    ));
+                    Duration::from_secs(default)
+                }
+            },
+            None => Duration::from_secs(value.unwrap_or(default)),
+        }
+    };
+
+    let connect_timeout = seconds("CONNECT_TIMEOUT_SECS", file.connect_timeout_secs, 10);
+    let request_timeout = seconds("REQUEST_TIMEOUT_SECS", file.request_timeout_secs, 60);
+
+    let env_headers = vars
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .filter_map(|(name, value)| {
+            name.strip_prefix("DEFAULT_HEADER_")
+                .map(|system_id| (system_id.to_string(), value.clone()))
+        });
+
+    let mut default_headers = HashMap::new();
+    for (system_id, header) in file.default_headers.into_iter().chain(env_headers) {
+        match system_id.parse() {
+            Ok(system_id) => {
+                default_headers.insert(system_id, header);
+            }
+            Err(_) => problems.push(format!(
+                "Default header system id {} is not valid",
+                system_id
+            )),
+        }
+    }
+
+    // The default number is always registered, so replies to it can be sent
+    let mut senders = vec![Sender {
+        name: "default".to_string(),
+        phone_number_id: phone_number_id.clone(),
+        systems: vec![],
+    }];
+
+    let mut file_senders: Vec<(String, FileSender)> = file.senders.into_iter().collect();
+    file_senders.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (name, sender) in file_senders {
+        if name == "default" {
+            problems.push("Sender name default is reserved for phone_number_id".to_string());
+            continue;
+        }
+
+        if sender.phone_number_id.is_empty() {
+            problems.push(format!("Sender {} has no phone_number_id", name));
+        }
+
+        for system_id in &sender.systems {
+            if let Some(other) = senders
+                .iter()
+                .find(|other| other.systems.contains(system_id))
+            {
+                problems.push(format!(
+                    "System {} is assigned to senders {} and {}",
+                    system_id, other.name, name
+                ));
+            }
+        }
+
+        senders.push(Sender {
+            name,
+            phone_number_id: sender.phone_number_id,
+            systems: sender.systems,
+        });
+    }
+
+    if !problems.is_empty() {
+        return Err(ConfigError(problems));
+    }
+
+    Ok(Config {
+        graph_base_url: env("GRAPH_BASE_URL")
+            .or(file.graph_base_url)
+            .unwrap_or("https://graph.facebook.com".to_string()),
+        graph_api_version: env("GRAPH_API_VERSION")
+            .or(file.graph_api_version)
+            .unwrap_or("v15.0".to_string()),
+        phone_number_id,
+        senders,
+        meta_token,
+        app_secret,
+        verify_token,
+        business_account_id: env("META_BUSINESS_ACCOUNT_ID").or(file.business_account_id),
+        redis_url,
+        connect_timeout,
+        request_timeout,
+        media_storage_path: env("MEDIA_STORAGE_PATH")
+            .or(file.media_storage_path)
+            .unwrap_or("media".to_string()),
+        // Button messages always had this header, it is kept unless configured
+        default_header: env("DEFAULT_HEADER")
+            .or(file.default_header)
+            .unwrap_or("Pescara Auto".to_string()),
+        default_headers,
+    })
+}
+
+// Configuration of the tests that go through get(), the environment of the tests isn't used
+#[cfg(test)]
+pub fn init_for_tests() -> &'static Config {
+    CONFIG.get_or_init(|| merge(&tests::vars(&[]), FileConfig::default()).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub fn vars(extra: &[(&str, &str)]) -> HashMap<String, String> {
+        [
+            ("PHONE_NUMBER_ID", "110000391967238"),
+            ("META_TOKEN", "meta-token"),
+            ("REDIS_URL", "redis://localhost"),
+            ("APP_SECRET", "test-app-secret"),
+            ("VERIFY_TOKEN", "token with spaces"),
+        ]
+        .iter()
+        .chain(extra)
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+    }
+
+    fn file(content: &str) -> FileConfig {
+        toml::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn uses_defaults_without_file() {
+        let config = merge(&vars(&[]), FileConfig::default()).unwrap();
+
+        assert_eq!(config.graph_url(), "https://graph.facebook.com/v15.0");
+        assert_eq!(config.app_secret, "test-app-secret");
+        assert_eq!(config.verify_token, "token with spaces");
+        assert_eq!(config.connect_timeout, Duration::from_secs(10));
+        assert_eq!(config.request_timeout, Duration::from_secs(60));
+        assert_eq!(config.media_storage_path, "media");
+        assert_eq!(config.default_header(1), "Pescara Auto");
+        assert_eq!(config.sender(None, 1), Ok("110000391967238"));
+    }
+
+    #[test]
+    fn environment_takes_precedence_over_file() {
+        let file = file(
+            r#"
+            phone_number_id = "110000391967299"
+            graph_base_url = "http://localhost:9000/"
+            graph_api_version = "v18.0"
+            request_timeout_secs = 30
+            default_header = "Whatsapp Manager"
+
+            [default_headers]
+            2 = "Pescara Auto"
+            3 = "Norte"
+            "#,
+        );
+        let vars = vars(&[("REQUEST_TIMEOUT_SECS", "5"), ("DEFAULT_HEADER_3", "Sur")]);
+
+        let config = merge(&vars, file).unwrap();
+
+        assert_eq!(config.phone_number_id, "110000391967238");
+        assert_eq!(config.graph_url(), "http://localhost:9000/v18.0");
+        assert_eq!(config.request_timeout, Duration::from_secs(5));
+        assert_eq!(config.default_header(1), "Whatsapp Manager");
+        assert_eq!(config.default_header(2), "Pescara Auto");
+        assert_eq!(config.default_header(3), "Sur");
+    }
+
+    #[test]
+    fn required_values_can_come_from_file() {
+        let file = file(
+            r#"
+            app_secret = "file-app-secret"
+            verify_token = "file-token"
+            "#,
+        );
+        let vars: HashMap<String, String> = vars(&[])
+            .into_iter()
+            .filter(|(name, _)| name != "APP_SECRET" && name != "VERIFY_TOKEN")
+            .collect();
+
+        let config = merge(&vars, file).unwrap();
+
+        assert_eq!(config.app_secret, "file-app-secret");
+        assert_eq!(config.verify_token, "file-token");
+    }
+
+    #[test]
+    fn empty_environment_values_fall_back_to_file() {
+        let file = file(
+            r#"
+            app_secret = "file-app-secret"
+            graph_base_url = "http://localhost:9000/"
+            request_timeout_secs = 30
+            default_header = "Whatsapp Manager"
+
+            [default_headers]
+            2 = "Norte"
+            "#,
+        );
+        let vars = vars(&[
+            ("APP_SECRET", ""),
+            ("GRAPH_BASE_URL", ""),
+            ("REQUEST_TIMEOUT_SECS", ""),
+            ("DEFAULT_HEADER", ""),
+            ("DEFAULT_HEADER_2", ""),
+        ]);
+
+        let config = merge(&vars, file).unwrap();
+
+        assert_eq!(config.app_secret, "file-app-secret");
+        assert_eq!(config.graph_url(), "http://localhost:9000/v15.0");
+        assert_eq!(config.request_timeout, Duration::from_secs(30));
+        assert_eq!(config.default_header(1), "Whatsapp Manager");
+        assert_eq!(config.default_header(2), "Norte");
+    }
+
+    #[test]
+    fn registers_senders_from_file() {
+        let file = file(
+            r#"
+            [senders.norte]
+            phone_number_id = "110000391967299"
+            systems = [2, 3]
+            "#,
+        );
+
+        let config = merge(&vars(&[]), file).unwrap();
+
+        assert_eq!(config.sender(None, 2), Ok("110000391967299"));
+        assert_eq!(config.sender(Some("norte"), 1), Ok("110000391967299"));
+        assert_eq!(config.sender(None, 1), Ok("110000391967238"));
+        assert!(config.sender(Some("sur"), 1).is_err());
+    }
+
+    #[test]
+    fn lists_every_missing_value() {
+        let err = merge(&HashMap::new(), FileConfig::default()).err().unwrap();
+
+        assert_eq!(
+            err.0,
+            vec![
+                "PHONE_NUMBER_ID (or phone_number_id in the config file) is missing",
+                "META_TOKEN (or meta_token in the config file) is missing",
+                "APP_SECRET (or app_secret in the config file) is missing",
+                "VERIFY_TOKEN (or verify_token in the config file) is missing",
+                "REDIS_URL (or redis_url in the config file) is missing",
+            ]
+        );
+    }
+
+    #[test]
+    fn lists_every_invalid_value() {
+        let vars = vars(&[
+            ("VERIFY_TOKEN", ""),
+            ("CONNECT_TIMEOUT_SECS", "ten"),
+            ("DEFAULT_HEADER_main", "Pescara Auto"),
+        ]);
+
+        let err = merge(&vars, FileConfig::default()).err().unwrap();
+
+        assert_eq!(
+            err.0,
+            vec![
+                "VERIFY_TOKEN (or verify_token in the config file) is missing",
+                "CONNECT_TIMEOUT_SECS must be a number of seconds, found ten",
+                "Default header system id main is not valid",
+            ]
+        );
+    }
+}
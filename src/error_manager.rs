@@ -1,10 +1,263 @@
+use crate::config::ConfigError;
+use crate::structs::ResponseError;
 use log::error;
-use redis::{ErrorKind, RedisError};
+use redis::RedisError;
+use serde_derive::Deserialize;
 use std::error::Error;
+use std::fmt;
 
-pub fn get_public_error(error: &RedisError) -> String {
-    // Match kind of error
-    error!("{:?} - {}", error.kind(), error.cause().unwrap());
+#[derive(Debug)]
+pub enum ManagerError {
+    // The request breaks meta rules, or the builder was used with a wrong type combination
+    Validation(String),
+    OutsideServiceWindow(String),
+    NotFound(String),
+    Unauthorized(String),
+    // Meta answered the call with an error
+    GraphApi(GraphApiError),
+    // Meta couldn't be reached or its answer couldn't be read
+    MetaUnavailable(String),
+    Redis(RedisError),
+    Configuration(String),
+    Internal(String),
+}
+
+// Error object returned by the graph api, status is the http status of the response
+#[derive(Debug, Deserialize, Clone)]
+pub struct GraphApiError {
+    #[serde(skip)]
+    pub status: u16,
+    pub message: String,
+    #[serde(rename = "type")]
+    pub error_type: Option<String>,
+    pub code: Option<i64>,
+    pub error_subcode: Option<i64>,
+    pub fbtrace_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GraphErrorBody {
+    error: GraphApiError,
+}
+
+impl ManagerError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ManagerError::Validation(_) => "VALIDATION_ERROR",
+            ManagerError::OutsideServiceWindow(_) => "OUTSIDE_SERVICE_WINDOW",
+            ManagerError::NotFound(_) => "NOT_FOUND",
+            ManagerError::Unauthorized(_) => "UNAUTHORIZED",
+            ManagerError::GraphApi(_) => "GRAPH_API_ERROR",
+            ManagerError::MetaUnavailable(_) => "META_UNAVAILABLE",
+            ManagerError::Redis(_) => "STORAGE_UNAVAILABLE",
+            ManagerError::Configuration(_) => "CONFIGURATION_ERROR",
+            ManagerError::Internal(_) => "INTERNAL_ERROR",
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        match self {
+            ManagerError::Validation(_) => 400,
+            ManagerError::OutsideServiceWindow(_) => 422,
+            ManagerError::NotFound(_) => 404,
+            ManagerError::Unauthorized(_) => 401,
+            ManagerError::GraphApi(err) => match err.status {
+                // Rate limited, the caller can retry later
+                429 => 429,
+                // Our token was rejected, nothing the caller can fix
+                401 | 403 => 502,
+                400..=499 => 422,
+                _ => 502,
+            },
+            ManagerError::MetaUnavailable(_) => 503,
+            ManagerError::Redis(_) => 503,
+            ManagerError::Configuration(_) => 500,
+            ManagerError::Internal(_) => 500,
+        }
+    }
+
+    // Message safe to return to callers, storage details are only logged
+    pub fn public_message(&self) -> String {
+        match self {
+            ManagerError::Redis(err) => {
+                error!("{:?} - {}", err.kind(), err);
+                String::from("Couldn't connect to server, retry later")
+            }
+            err => err.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ManagerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManagerError::Validation(message)
+            | ManagerError::OutsideServiceWindow(message)
+            | ManagerError::NotFound(message)
+            | ManagerError::Unauthorized(message)
+            | ManagerError::MetaUnavailable(message)
+            | ManagerError::Configuration(message)
+            | ManagerError::Internal(message) => write!(f, "{}", message),
+            ManagerError::GraphApi(err) => write!(f, "Meta rejected the request: {}", err.message),
+            ManagerError::Redis(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ManagerError {}
+
+impl From<RedisError> for ManagerError {
+    fn from(err: RedisError) -> Self {
+        ManagerError::Redis(err)
+    }
+}
+
+// A response body that couldn't be read is an answer from meta we can't use
+pub fn unreadable_body(err: std::io::Error) -> ManagerError {
+    ManagerError::MetaUnavailable(format!("Couldnt read meta response: {}", err))
+}
+
+impl From<ConfigError> for ManagerError {
+    fn from(err: ConfigError) -> Self {
+        ManagerError::Configuration(err.to_string())
+    }
+}
+
+impl From<ureq::Error> for ManagerError {
+    fn from(err: ureq::Error) -> Self {
+        match err {
+            ureq::Error::Status(status, response) => {
+                let body = response.into_string().unwrap_or_default();
+
+                let mut graph_error = match serde_json::from_str::<GraphErrorBody>(&body) {
+                    Ok(body) => body.error,
+                    Err(_) => GraphApiError {
+                        status,
+                        message: body,
+                        error_type: None,
+                        code: None,
+                        error_subcode: None,
+                        fbtrace_id: None,
+                    },
+                };
+                graph_error.status = status;
+
+                error!("Graph api error: {:?}", graph_error);
+                ManagerError::GraphApi(graph_error)
+            }
+            ureq::Error::Transport(transport) => {
+                ManagerError::MetaUnavailable(transport.to_string())
+            }
+        }
+    }
+}
+
+impl From<ManagerError> for ResponseError {
+    fn from(err: ManagerError) -> Self {
+        let graph_error = match &err {
+            ManagerError::GraphApi(graph_error) => Some(graph_error.clone()),
+            _ => None,
+        };
+
+        ResponseError {
+            code: err.code().to_string(),
+            message: err.public_message(),
+            status: err.status(),
+            meta_type: graph_error.as_ref().and_then(|e| e.error_type.clone()),
+            meta_code: graph_error.as_ref().and_then(|e| e.code),
+            meta_subcode: graph_error.as_ref().and_then(|e| e.error_subcode),
+            fbtrace_id: graph_error.and_then(|e| e.fbtrace_id),
+        }
+    }
+}
+
+impl From<RedisError> for ResponseError {
+    fn from(err: RedisError) -> Self {
+        ManagerError::Redis(err).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph_error(status: u16, body: &str) -> ManagerError {
+        let response = ureq::Response::new(status, "Graph Error", body).unwrap();
+
+        ureq::Error::Status(status, response).into()
+    }
+
+    fn graph_body(code: i64) -> String {
+        format!(
+            r#"{{"error": {{"message": "(#{}) Meta error", "type": "OAuthException", "code": {}, "error_subcode": 2494010, "fbtrace_id": "AbCdEfGh123"}}}}"#,
+            code, code
+        )
+    }
+
+    #[test]
+    fn maps_graph_api_status() {
+        for (status, expected) in [
+            (400, 422),
+            (404, 422),
+            (401, 502),
+            (403, 502),
+            (429, 429),
+            (500, 502),
+            (503, 502),
+        ] {
+            assert_eq!(
+                graph_error(status, &graph_body(100)).status(),
+                expected,
+                "{}",
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_graph_api_error_fields() {
+        let err = graph_error(400, &graph_body(131030));
+        assert_eq!(err.code(), "GRAPH_API_ERROR");
+        assert_eq!(
+            err.to_string(),
+            "Meta rejected the request: (#131030) Meta error"
+        );
+
+        let response: ResponseError = err.into();
+        assert_eq!(response.status, 422);
+        assert_eq!(response.meta_type.as_deref(), Some("OAuthException"));
+        assert_eq!(response.meta_code, Some(131030));
+        assert_eq!(response.meta_subcode, Some(2494010));
+        assert_eq!(response.fbtrace_id.as_deref(), Some("AbCdEfGh123"));
+    }
+
+    #[test]
+    fn falls_back_to_body_when_not_json() {
+        let err = graph_error(502, "<html>Bad Gateway</html>");
+
+        match &err {
+            ManagerError::GraphApi(graph_error) => {
+                assert_eq!(graph_error.status, 502);
+                assert_eq!(graph_error.message, "<html>Bad Gateway</html>");
+                assert!(graph_error.code.is_none());
+            }
+            err => panic!("Expected a graph api error, found {:?}", err),
+        }
+
+        let response: ResponseError = err.into();
+        assert_eq!(response.status, 502);
+        assert!(response.meta_type.is_none());
+        assert!(response.meta_code.is_none());
+    }
+
+    #[test]
+    fn unreadable_body_is_meta_unavailable() {
+        let err = unreadable_body(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "eof",
+        ));
 
-    String::from("Couldn't connect to server, retry later")
+        assert_eq!(err.code(), "META_UNAVAILABLE");
+        assert_eq!(err.status(), 503);
+    }
 }
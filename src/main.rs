@@ -1,41 +1,92 @@
 extern crate core;
 
+mod blob_store;
+mod config;
 mod error_manager;
+mod media;
 mod redis;
 mod request_builder;
 mod request_handler;
 mod requests;
 mod structs;
+mod templates;
+mod vcard;
+mod webhook_security;
+mod webhook_worker;
 
-use crate::error_manager::get_public_error;
-use crate::redis::{create_message, log_message, publish_message, store_message};
-use crate::request_builder::{MessageContent, MessageResponse};
+use crate::error_manager::ManagerError;
+use crate::redis::{enqueue_webhook_event, WEBHOOK_STREAM};
+use crate::request_builder::MessageRequest;
 use crate::structs::webhooks::Event;
 use crate::structs::{MessageLog, ModifiedReference, StandardResponse};
+use crate::templates::TemplateDefinition;
+use crate::webhook_security::VerificationQuery;
 use ::redis::RedisError;
+use actix_multipart::form::bytes::Bytes;
+use actix_multipart::form::{MultipartForm, MultipartFormConfig};
+use actix_web::http::StatusCode;
 use actix_web::middleware::Logger;
-use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
-use log::{debug, error, trace};
+use actix_web::{delete, get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use log::{debug, error, info, trace};
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::env;
 use std::error::Error;
 use std::time::{SystemTime, UNIX_EPOCH};
-use fizzy_commons::shared_structs::MessageRequest;
 
 static SYSTEM_ID: &str = "01";
 
+#[derive(MultipartForm)]
+struct MediaUpload {
+    file: Bytes,
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
+    // Fail on boot listing every missing value, instead of on the first request that needs one
+    if let Err(err) = config::init() {
+        error!("{}", err);
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            err.to_string(),
+        ));
+    }
+
+    // Conversations from before business numbers were namespaced belong to the default one
+    match redis::migrate_conversation_keys() {
+        Ok(0) => {}
+        Ok(renamed) => info!(
+            "Migrated {} conversation keys to the default number",
+            renamed
+        ),
+        Err(err) => {
+            error!("Couldnt migrate conversation keys: {}", err);
+            return Err(std::io::Error::other(err.to_string()));
+        }
+    }
+
+    // Webhook events are processed in background from the redis stream
+    webhook_worker::start();
+
     HttpServer::new(|| {
         App::new()
             .wrap(Logger::new("%U").log_target("INFO"))
+            .app_data(
+                MultipartFormConfig::default()
+                    .total_limit(media::MAX_MEDIA_SIZE as usize)
+                    .memory_limit(media::MAX_MEDIA_SIZE as usize),
+            )
             .service(health)
             .service(webhook)
             .service(validate)
             .service(send_message)
+            .service(upload_media)
+            .service(message_status)
+            .service(mark_read)
+            .service(list_templates)
+            .service(create_template)
+            .service(delete_template)
             .service(incoming_messages)
             .service(outgoing_messages)
     })
@@ -44,19 +95,63 @@ async fn main() -> std::io::Result<()> {
     .await
 }
 
+// Failed responses are returned with the http status of their first error
+fn error_response(response: &StandardResponse) -> HttpResponse {
+    let status =
+        StatusCode::from_u16(response.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+    HttpResponse::build(status).body(serde_json::to_string(response).unwrap())
+}
+
 #[get("/health")]
 async fn health() -> impl Responder {
-    "OK"
+    // Webhooks are acknowledged and enqueued, without the worker they would never be processed
+    if !webhook_worker::is_alive() {
+        return HttpResponse::ServiceUnavailable().body("Webhook worker is not running");
+    }
+
+    HttpResponse::Ok().body("OK")
 }
 
 #[post("/webhook")]
-async fn webhook(event: web::Json<Event>) -> impl Responder {
-    let response = request_handler::webhook_message(event.0);
+async fn webhook(request: HttpRequest, body: web::Bytes) -> impl Responder {
+    let mut response = StandardResponse::new();
 
-    match response {
-        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
-        Err(response) => {
-            HttpResponse::InternalServerError().body(serde_json::to_string(&response).unwrap())
+    // Only meta knows the app secret, anything else is rejected before being processed
+    let signature = request
+        .headers()
+        .get(webhook_security::SIGNATURE_HEADER)
+        .and_then(|header| header.to_str().ok());
+
+    if let Err(err) =
+        webhook_security::verify_signature(&body, signature, &config::get().app_secret)
+    {
+        error!("Rejected webhook: {}", err);
+        return error_response(&StandardResponse::error(ManagerError::Unauthorized(
+            err.to_string(),
+        )));
+    }
+
+    if let Err(err) = serde_json::from_slice::<Event>(&body) {
+        error!("Couldnt parse webhook event: {}", err);
+        return error_response(&StandardResponse::error(ManagerError::Validation(
+            err.to_string(),
+        )));
+    }
+
+    // Acknowledge meta right away, the event is processed by the webhook worker
+    match enqueue_webhook_event(&String::from_utf8_lossy(&body)) {
+        Ok(id) => {
+            response.references.push(ModifiedReference {
+                system: "REDIS".to_string(),
+                reference: format!("{}:{}", WEBHOOK_STREAM, id),
+            });
+            HttpResponse::Ok().body(serde_json::to_string(&response).unwrap())
+        }
+        Err(err) => {
+            error!("Couldnt enqueue webhook event: {}", err);
+            response.errors = Some(vec![err.into()]);
+            error_response(&response)
         }
     }
 }
@@ -67,9 +162,7 @@ async fn incoming_messages(log: web::Json<MessageLog>) -> impl Responder {
 
     match response {
         Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
-        Err(response) => {
-            HttpResponse::InternalServerError().body(serde_json::to_string(&response).unwrap())
-        }
+        Err(response) => error_response(&response),
     }
 }
 
@@ -88,36 +181,210 @@ async fn outgoing_messages(log: web::Json<MessageLog>) -> impl Responder {
 }
 
 #[get("/webhook")]
-async fn validate(validation_parameters: HttpRequest) -> impl Responder {
-    let verify_token = match env::var("VERIFY_TOKEN") {
-        Ok(x) => x,
-        Err(err) => panic!("{}", err),
-    };
+async fn validate(query: web::Query<VerificationQuery>) -> impl Responder {
+    debug!("Webhook verification with mode {}", query.mode);
 
-    let mut param_map = HashMap::new();
+    match webhook_security::verify_subscription(&query, &config::get().verify_token) {
+        Ok(challenge) => HttpResponse::Ok().body(challenge),
+        Err(err) => {
+            error!("Rejected webhook verification: {}", err);
+            HttpResponse::Forbidden().body(err.to_string())
+        }
+    }
+}
+
+#[post("/message")]
+async fn send_message(message: web::Json<MessageRequest>) -> impl Responder {
+    let response = request_handler::send_message(message.0);
 
-    for param in validation_parameters.query_string().split("&") {
-        let param_vec: Vec<&str> = param.split("=").collect();
-        param_map.insert(param_vec[0], param_vec[1]);
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => error_response(&response),
     }
+}
+
+#[get("/message/{wamid}/status")]
+async fn message_status(wamid: web::Path<String>) -> impl Responder {
+    let response = request_handler::get_message_status(&wamid);
 
-    if verify_token != param_map.get("hub.verify_token").unwrap().to_string() {
-        panic!("Received verification token is not equals to defined one")
+    match response {
+        Ok(Some(status)) => HttpResponse::Ok().body(serde_json::to_string(&status).unwrap()),
+        Ok(None) => error_response(&StandardResponse::error(ManagerError::NotFound(format!(
+            "No status found for message {}",
+            wamid
+        )))),
+        Err(response) => error_response(&response),
     }
+}
+
+#[derive(Deserialize)]
+struct ReadQuery {
+    typing: Option<bool>,
+    // Business number that received the message, the default one when not set
+    sender: Option<String>,
+}
 
-    debug!("{:?}", &param_map);
+#[post("/message/{wamid}/read")]
+async fn mark_read(wamid: web::Path<String>, query: web::Query<ReadQuery>) -> impl Responder {
+    let response = request_handler::mark_message_read(
+        &wamid,
+        query.sender.as_deref(),
+        query.typing.unwrap_or(false),
+    );
 
-    HttpResponse::Ok().body(param_map.get("hub.challenge").unwrap().to_string())
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => error_response(&response),
+    }
 }
 
-#[post("/message")]
-async fn send_message(message: web::Json<MessageRequest>) -> impl Responder {
-    let response = request_handler::send_message(message.0);
+#[derive(Deserialize)]
+struct TemplateListQuery {
+    refresh: Option<bool>,
+}
+
+#[get("/templates")]
+async fn list_templates(query: web::Query<TemplateListQuery>) -> impl Responder {
+    let response = request_handler::list_templates(query.refresh.unwrap_or(false));
+
+    match response {
+        Ok(templates) => HttpResponse::Ok().body(serde_json::to_string(&templates).unwrap()),
+        Err(response) => error_response(&response),
+    }
+}
+
+#[post("/templates")]
+async fn create_template(template: web::Json<TemplateDefinition>) -> impl Responder {
+    let response = request_handler::create_template(template.0);
+
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => error_response(&response),
+    }
+}
+
+#[delete("/templates/{name}")]
+async fn delete_template(name: web::Path<String>) -> impl Responder {
+    let response = request_handler::delete_template(&name);
 
     match response {
         Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
-        Err(response) => {
-            HttpResponse::InternalServerError().body(serde_json::to_string(&response).unwrap())
+        Err(response) => error_response(&response),
+    }
+}
+
+#[post("/media")]
+async fn upload_media(form: MultipartForm<MediaUpload>) -> impl Responder {
+    let file = form.into_inner().file;
+
+    let mime_type = match &file.content_type {
+        Some(mime_type) => mime_type.essence_str().to_string(),
+        None => {
+            return error_response(&StandardResponse::error(ManagerError::Validation(
+                "File content type is required".to_string(),
+            )))
         }
+    };
+
+    let response = request_handler::upload_media(&file.data, &mime_type, file.file_name);
+
+    match response {
+        Ok(response) => HttpResponse::Ok().body(serde_json::to_string(&response).unwrap()),
+        Err(response) => error_response(&response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    const TEXT_MESSAGE: &[u8] = include_bytes!("../tests/fixtures/webhook_text_message.json");
+
+    #[actix_web::test]
+    async fn validate_returns_challenge() {
+        config::init_for_tests();
+        let app = test::init_service(App::new().service(validate)).await;
+
+        let request = test::TestRequest::get()
+            .uri("/webhook?hub.mode=subscribe&hub.verify_token=token%20with%20spaces&hub.challenge=1158201444")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(test::read_body(response).await, "1158201444");
+    }
+
+    #[actix_web::test]
+    async fn validate_rejects_wrong_token_and_mode() {
+        config::init_for_tests();
+        let app = test::init_service(App::new().service(validate)).await;
+
+        for uri in [
+            "/webhook?hub.mode=subscribe&hub.verify_token=another&hub.challenge=1158201444",
+            "/webhook?hub.mode=unsubscribe&hub.verify_token=token%20with%20spaces&hub.challenge=1",
+            "/webhook?hub.mode&hub.verify_token=token%20with%20spaces&hub.challenge=1158201444",
+        ] {
+            let request = test::TestRequest::get().uri(uri).to_request();
+            let response = test::call_service(&app, request).await;
+
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn validate_rejects_missing_parameters() {
+        let app = test::init_service(App::new().service(validate)).await;
+
+        for uri in [
+            "/webhook",
+            "/webhook?hub.mode=subscribe&hub.verify_token=token",
+            "/webhook?garbage",
+        ] {
+            let request = test::TestRequest::get().uri(uri).to_request();
+            let response = test::call_service(&app, request).await;
+
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn health_fails_without_webhook_worker() {
+        let app = test::init_service(App::new().service(health)).await;
+
+        let request = test::TestRequest::get().uri("/health").to_request();
+        let response = test::call_service(&app, request).await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_web::test]
+    async fn webhook_rejects_unsigned_and_tampered_payloads() {
+        config::init_for_tests();
+        let app = test::init_service(App::new().service(webhook)).await;
+
+        let unsigned = test::TestRequest::post()
+            .uri("/webhook")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(TEXT_MESSAGE)
+            .to_request();
+        let response = test::call_service(&app, unsigned).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let tampered = test::TestRequest::post()
+            .uri("/webhook")
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header((
+                webhook_security::SIGNATURE_HEADER,
+                "sha256=1cd8aae2de23a7f9bacb6028c69516e2fcc0902b05fe031391da8294f28d236c",
+            ))
+            .set_payload(
+                String::from_utf8(TEXT_MESSAGE.to_vec())
+                    .unwrap()
+                    .replace("aceite", "aire"),
+            )
+            .to_request();
+        let response = test::call_service(&app, tampered).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
     }
 }
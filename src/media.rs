@@ -0,0 +1,237 @@
+use crate::blob_store;
+use crate::blob_store::BlobStore;
+use crate::config;
+use crate::error_manager::{unreadable_body, ManagerError};
+use crate::request_builder::{graph_agent, MessageType};
+use crate::structs::webhooks::MediaData;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{debug, error, info};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+
+// Largest media accepted by meta (documents)
+pub const MAX_MEDIA_SIZE: u64 = 100 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MediaUploadResponse {
+    pub id: String,
+}
+
+pub fn media_hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+// Checks the file against meta limits for the media type matching its mime type
+pub fn validate_upload(data: &[u8], mime_type: &str) -> Result<MessageType, ManagerError> {
+    let media_type = match MessageType::from_mime_type(mime_type) {
+        Some(media_type) => media_type,
+        None => {
+            return Err(ManagerError::Validation(format!(
+                "Mime type {} is not supported by whatsapp",
+                mime_type
+            )))
+        }
+    };
+
+    let limits = media_type.media_limits().ok_or_else(|| {
+        ManagerError::Validation(format!(
+            "{} media has no upload limits",
+            media_type.as_str()
+        ))
+    })?;
+    if data.len() as u64 > limits.max_size {
+        return Err(ManagerError::Validation(format!(
+            "File size {} exceeds the {} bytes limit for {} media",
+            data.len(),
+            limits.max_size,
+            media_type.as_str()
+        )));
+    }
+
+    Ok(media_type)
+}
+
+pub fn upload_media(
+    data: &[u8],
+    mime_type: &str,
+    filename: &str,
+) -> Result<MediaUploadResponse, ManagerError> {
+    let boundary = format!("----whatsapp-manager-{}", &media_hash(data)[..16]);
+
+    // ureq has no multipart support, so the form body is built by hand
+    let mut body: Vec<u8> = vec![];
+    for (name, value) in [("messaging_product", "whatsapp"), ("type", mime_type)] {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
+            )
+            .as_bytes(),
+        );
+    }
+    body.extend_from_slice(
+        format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+            boundary,
+            form_filename(filename, data),
+            mime_type
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(data);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    debug!("Uploading {} bytes of {}", data.len(), mime_type);
+    let response_body = graph_agent()
+        .post(
+            format!(
+                "{}/{}/media",
+                config::get().graph_url(),
+                config::get().phone_number_id
+            )
+            .as_str(),
+        )
+        .set("Authorization", config::get().authorization().as_str())
+        .set(
+            "Content-Type",
+            format!("multipart/form-data; boundary={}", boundary).as_str(),
+        )
+        .send_bytes(&body)?
+        .into_string()
+        .map_err(unreadable_body)?;
+
+    match serde_json::from_str(&response_body) {
+        Ok(response) => Ok(response),
+        Err(err) => {
+            error!("Couldnt parse media upload response: {}", response_body);
+            Err(ManagerError::MetaUnavailable(format!(
+                "Couldnt parse media upload response: {}",
+                err
+            )))
+        }
+    }
+}
+
+// The filename comes from the caller, line breaks would let it add headers to the form part
+fn form_filename(filename: &str, data: &[u8]) -> String {
+    let filename: String = filename
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"' && *c != '\\')
+        .collect();
+
+    match filename.trim() {
+        "" => media_hash(data),
+        filename => filename.to_string(),
+    }
+}
+
+// Resolves the temporary download url of a media id
+pub fn get_media(media_id: &str) -> Result<MediaData, ManagerError> {
+    let response_body = graph_agent()
+        .get(format!("{}/{}", config::get().graph_url(), media_id).as_str())
+        .set("Authorization", config::get().authorization().as_str())
+        .call()?
+        .into_string()
+        .map_err(unreadable_body)?;
+
+    match serde_json::from_str(&response_body) {
+        Ok(media) => Ok(media),
+        Err(err) => {
+            error!("Couldnt parse media response: {}", response_body);
+            Err(ManagerError::MetaUnavailable(format!(
+                "Couldnt parse media response: {}",
+                err
+            )))
+        }
+    }
+}
+
+pub fn download_media(url: &str) -> Result<Vec<u8>, ManagerError> {
+    let mut data: Vec<u8> = vec![];
+
+    graph_agent()
+        .get(url)
+        .set("Authorization", config::get().authorization().as_str())
+        .call()?
+        .into_reader()
+        .take(MAX_MEDIA_SIZE + 1)
+        .read_to_end(&mut data)
+        .map_err(|err| ManagerError::MetaUnavailable(format!("Couldnt download media: {}", err)))?;
+
+    // Reading one byte over the limit tells a too large file apart from one of the exact size
+    if data.len() as u64 > MAX_MEDIA_SIZE {
+        return Err(ManagerError::Validation(format!(
+            "Media is larger than the {} bytes limit",
+            MAX_MEDIA_SIZE
+        )));
+    }
+
+    Ok(data)
+}
+
+// Storage key of an inbound media, webhook values are reduced to characters safe on a path
+pub fn media_key(phone_number: &str, message_id: &str) -> String {
+    format!("{}/{}", key_segment(phone_number), key_segment(message_id))
+}
+
+fn key_segment(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if blob_store::is_key_char(c) { c } else { '_' })
+        .collect()
+}
+
+// Meta sends the hash hex encoded on the media endpoint and base64 encoded on some webhooks
+pub fn verify_hash(data: &[u8], expected: &str) -> Result<(), ManagerError> {
+    let digest = Sha256::digest(data);
+
+    if hex::encode(digest).eq_ignore_ascii_case(expected) || STANDARD.encode(digest) == expected {
+        return Ok(());
+    }
+
+    Err(ManagerError::MetaUnavailable(format!(
+        "Media sha256 mismatch, expected {}",
+        expected
+    )))
+}
+
+// Downloads an inbound media and stores it under the given key, returning the storage reference
+pub fn persist_media(
+    media_id: &str,
+    sha256: &str,
+    key: &str,
+    store: &dyn BlobStore,
+) -> Result<String, ManagerError> {
+    info!("Resolving media {}", media_id);
+    let media = get_media(media_id)?;
+
+    info!("Downloading media {}", media_id);
+    let data = download_media(&media.url)?;
+
+    verify_hash(&data, sha256)?;
+
+    store.store(key, &data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_header_characters_from_filename() {
+        let data = b"%PDF-1.4";
+
+        assert_eq!(
+            form_filename("cotización 1234.pdf", data),
+            "cotización 1234.pdf"
+        );
+        assert_eq!(
+            form_filename("a.pdf\r\nContent-Type: text/html\r\n", data),
+            "a.pdfContent-Type: text/html"
+        );
+        assert_eq!(form_filename("\"a\\b\".pdf", data), "ab.pdf");
+        assert_eq!(form_filename("\r\n", data), media_hash(data));
+    }
+}
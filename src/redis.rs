@@ -1,17 +1,29 @@
+use crate::config;
+use crate::error_manager::ManagerError;
 use crate::request_builder;
-use crate::request_builder::{MessageBuilder, MessageResponse, MessageType};
-use crate::structs::webhooks::Event;
-use crate::structs::{MessageLog, Storable};
-use log::{debug, error, trace};
-use redis::{Client, Commands, ControlFlow, JsonCommands, PubSubCommands, RedisError, RedisResult};
+use crate::request_builder::{
+    HeaderContent, MessageBuilder, MessageRequest, MessageResponse, MessageType, TemplateMessage,
+};
+use crate::structs::webhooks::{Event, Status};
+use crate::structs::{MessageLog, StatusNotification};
+use crate::templates::TemplateDefinition;
+use crate::vcard;
+use log::{debug, error, trace, warn};
+use redis::streams::{
+    StreamId, StreamMaxlen, StreamPendingCountReply, StreamReadOptions, StreamReadReply,
+};
+use redis::{Client, Commands, ErrorKind, JsonCommands, RedisError, RedisResult};
 use serde::Serialize;
 use std::env::VarError;
-use std::error::Error;
-use fizzy_commons::shared_structs::MessageRequest;
+
+pub const WEBHOOK_STREAM: &str = "webhook-events";
+pub const WEBHOOK_DEAD_LETTER_STREAM: &str = "webhook-events-dead";
+pub const WEBHOOK_GROUP: &str = "webhook-processors";
+// Processed events are deleted, the cap only trims the oldest ones if the worker falls far behind
+const WEBHOOK_STREAM_MAXLEN: usize = 100_000;
 
 fn create_client() -> Result<Client, RedisError> {
-    let url = std::env::var("REDIS_URL").unwrap();
-    let client = redis::Client::open(url);
+    let client = redis::Client::open(config::get().redis_url.as_str());
 
     return match client {
         Ok(client) => unsafe { Ok(client) },
@@ -52,32 +64,55 @@ pub fn log_message(message: &MessageLog) -> Result<String, RedisError> {
 pub fn publish_message(
     message: &MessageLog,
     phone_number: &String,
-) -> Result<String, Box<dyn Error>> {
+) -> Result<String, ManagerError> {
     let client = create_client()?;
     let mut con = client.get_connection()?;
-    let _: () = con
-        .publish(
-            format!("whatsapp-notification:{}", phone_number),
-            serde_json::to_string(message).unwrap(),
-        )
-        .expect("err");
+    let _: () = con.publish(
+        format!("whatsapp-notification:{}", phone_number),
+        to_json(message)?,
+    )?;
 
     Ok("OK".to_string())
 }
 
+pub fn publish_status(notification: &StatusNotification) -> Result<String, ManagerError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    let channel = format!("whatsapp-status:{}", notification.destination_system);
+    let _: () = con.publish(&channel, to_json(notification)?)?;
+
+    Ok(channel)
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, ManagerError> {
+    serde_json::to_string(value)
+        .map_err(|err| ManagerError::Internal(format!("Couldnt serialize notification: {}", err)))
+}
+
 pub fn create_message(
     message: &MessageRequest,
     to: String,
-) -> Result<MessageResponse, Box<dyn Error>> {
-    let mut request = MessageBuilder::new();
+) -> Result<MessageResponse, ManagerError> {
+    let sender = config::get()
+        .sender(message.sender.as_deref(), message.system_id)
+        .map_err(ManagerError::Validation)?;
+
+    // Every message type starts from this base, so sender and replies work for all of them
+    let mut base = MessageBuilder::new();
+    base.from(sender.to_string());
+    if let Some(reply_to) = &message.reply_to {
+        base.reply_to(reply_to.clone());
+    }
     let mut responses: Vec<MessageResponse> = vec![];
 
-    return match MessageType::from_str(&message.message_type) {
+    match MessageType::from_str(&message.message_type)? {
         MessageType::Text => {
-            let request = MessageBuilder::new()
+            let request = base
+                .clone()
                 .message_type(MessageType::Text, None)
                 .to(to)
-                .body(message.clone().content.body.unwrap())
+                .body(required_body(message)?)
                 .execute();
 
             match request {
@@ -92,17 +127,27 @@ pub fn create_message(
             }
         }
         MessageType::InteractiveButton => {
-            let mut request = MessageBuilder::new()
+            let mut request = base
+                .clone()
                 .message_type(
                     MessageType::Interactive,
                     Some(MessageType::InteractiveButton),
                 )
                 .to(to)
-                .header("Pescara Auto".to_string())
-                .body(message.clone().content.body.unwrap())
+                .body(required_body(message)?)
                 .clone();
 
-            for button in &message.clone().content.buttons.unwrap().choices {
+            set_header_and_footer(
+                &mut request,
+                message,
+                Some(config::get().default_header(message.system_id)),
+            )?;
+
+            let buttons = message.content.buttons.as_ref().ok_or_else(|| {
+                ManagerError::Validation("Buttons are required for button messages".to_string())
+            })?;
+
+            for button in &buttons.choices {
                 request.add_reply_button(button, None);
             }
 
@@ -121,23 +166,264 @@ pub fn create_message(
         }
 
         MessageType::InteractiveList => {
-            let mut request = MessageBuilder::new()
+            let mut request = base
+                .clone()
+                .message_type(MessageType::Interactive, Some(MessageType::InteractiveList))
+                .to(to)
+                .body(required_body(message)?)
+                .clone();
+
+            let list = message.content.list.as_ref().ok_or_else(|| {
+                ManagerError::Validation("List is required for list messages".to_string())
+            })?;
+
+            request.set_button_title(list.button.as_deref().unwrap_or("Opciones"));
+
+            set_header_and_footer(&mut request, message, Some(String::from(&list.title)))?;
+
+            for button in &list.choices {
+                request.add_list_button(
+                    &button.value,
+                    Some(&button.id),
+                    button.description.as_deref(),
+                    "",
+                );
+            }
+
+            for section in list.sections.iter().flatten() {
+                for button in &section.choices {
+                    request.add_list_button(
+                        &button.value,
+                        Some(&button.id),
+                        button.description.as_deref(),
+                        &section.title,
+                    );
+                }
+            }
+
+            let response = request.execute();
+
+            match response {
+                Ok(response_body) => Ok(response_body),
+                Err(err) => {
+                    error!(
+                        "{}",
+                        format!("Couldnt proccess message creation: {}", err).as_str()
+                    );
+                    Err(err)
+                }
+            }
+        }
+        MessageType::Reaction => {
+            let reaction = match message.content.reaction.as_ref() {
+                Some(reaction) => reaction,
+                None => {
+                    error!("Reaction content is required for reaction messages");
+                    return Err(ManagerError::Validation(
+                        "Reaction content is required for reaction messages".to_string(),
+                    ));
+                }
+            };
+
+            let response = base
+                .clone()
+                .message_type(MessageType::Reaction, None)
+                .to(to)
+                .reaction(reaction.message_id.clone(), reaction.emoji.clone())
+                .execute();
+
+            match response {
+                Ok(response_body) => Ok(response_body),
+                Err(err) => {
+                    error!(
+                        "{}",
+                        format!("Couldnt proccess message creation: {}", err).as_str()
+                    );
+                    Err(err)
+                }
+            }
+        }
+        MessageType::Contacts => {
+            let mut contacts = message.content.contacts.clone().unwrap_or_default();
+            for vcard in message.content.vcards.iter().flatten() {
+                contacts.push(
+                    vcard::from_vcard(vcard).map_err(|err| {
+                        ManagerError::Validation(format!("Invalid vCard: {}", err))
+                    })?,
+                );
+            }
+
+            let response = base
+                .clone()
+                .message_type(MessageType::Contacts, None)
+                .to(to)
+                .contacts(contacts)
+                .execute();
+
+            match response {
+                Ok(response_body) => Ok(response_body),
+                Err(err) => {
+                    error!(
+                        "{}",
+                        format!("Couldnt proccess message creation: {}", err).as_str()
+                    );
+                    Err(err)
+                }
+            }
+        }
+        MessageType::Location => {
+            let location = match message.content.location.as_ref() {
+                Some(location) => location,
+                None => {
+                    error!("Location content is required for location messages");
+                    return Err(ManagerError::Validation(
+                        "Location content is required for location messages".to_string(),
+                    ));
+                }
+            };
+
+            let response = base
+                .clone()
+                .message_type(MessageType::Location, None)
+                .to(to)
+                .location(location.clone())
+                .execute();
+
+            match response {
+                Ok(response_body) => Ok(response_body),
+                Err(err) => {
+                    error!(
+                        "{}",
+                        format!("Couldnt proccess message creation: {}", err).as_str()
+                    );
+                    Err(err)
+                }
+            }
+        }
+        MessageType::InteractiveLocationRequest => {
+            let mut request = base
+                .clone()
                 .message_type(
                     MessageType::Interactive,
-                    Some(MessageType::InteractiveList),
+                    Some(MessageType::InteractiveLocationRequest),
                 )
                 .to(to)
-                .header(String::from(&message.clone().content.list.as_ref().unwrap().title))
-                .body(message.clone().content.body.unwrap())
-                .main_button_content("Opciones".to_string())
+                .body(message.content.body.clone().unwrap_or_default())
+                .request_location()
                 .clone();
 
-            for button in &message.clone().content.list.as_ref().unwrap().choices {
-                request.add_list_button(&button.value, Some(&button.id), &message.clone().content.list.as_ref().unwrap().title);
+            if let Some(footer) = &message.content.footer {
+                request.footer(footer.clone());
+            }
+
+            let response = request.execute();
+
+            match response {
+                Ok(response_body) => Ok(response_body),
+                Err(err) => {
+                    error!(
+                        "{}",
+                        format!("Couldnt proccess message creation: {}", err).as_str()
+                    );
+                    Err(err)
+                }
             }
+        }
+        MessageType::InteractiveCtaUrl => {
+            let cta_url = match message.content.cta_url.as_ref() {
+                Some(cta_url) => cta_url,
+                None => {
+                    error!("Call to action content is required for cta_url messages");
+                    return Err(ManagerError::Validation(
+                        "Call to action content is required for cta_url messages".to_string(),
+                    ));
+                }
+            };
+
+            let mut request = base
+                .clone()
+                .message_type(
+                    MessageType::Interactive,
+                    Some(MessageType::InteractiveCtaUrl),
+                )
+                .to(to)
+                .body(message.content.body.clone().unwrap_or_default())
+                .cta_url(cta_url.display_text.clone(), cta_url.url.clone())
+                .clone();
+
+            set_header_and_footer(&mut request, message, None)?;
 
             let response = request.execute();
 
+            match response {
+                Ok(response_body) => Ok(response_body),
+                Err(err) => {
+                    error!(
+                        "{}",
+                        format!("Couldnt proccess message creation: {}", err).as_str()
+                    );
+                    Err(err)
+                }
+            }
+        }
+        MessageType::Image
+        | MessageType::Document
+        | MessageType::Audio
+        | MessageType::Video
+        | MessageType::Sticker => {
+            let media_type = MessageType::from_str(&message.message_type)?;
+            let media = match message.content.media.as_ref() {
+                Some(media) => media,
+                None => {
+                    error!(
+                        "Media content is required for {} messages",
+                        message.message_type
+                    );
+                    return Err(ManagerError::Validation(format!(
+                        "Media content is required for {} messages",
+                        message.message_type
+                    )));
+                }
+            };
+
+            media.validate(media_type)?;
+
+            let response = base
+                .clone()
+                .message_type(media_type, None)
+                .to(to)
+                .media(media.to_media())
+                .execute();
+
+            match response {
+                Ok(response_body) => Ok(response_body),
+                Err(err) => {
+                    error!(
+                        "{}",
+                        format!("Couldnt proccess message creation: {}", err).as_str()
+                    );
+                    Err(err)
+                }
+            }
+        }
+        MessageType::Template => {
+            let template = match message.content.template.as_ref() {
+                Some(template) => template,
+                None => {
+                    error!("Template content is required for template messages");
+                    return Err(ManagerError::Validation(
+                        "Template content is required for template messages".to_string(),
+                    ));
+                }
+            };
+
+            let response = base
+                .clone()
+                .message_type(MessageType::Template, None)
+                .to(to)
+                .template(template.to_template())
+                .execute();
+
             match response {
                 Ok(response_body) => Ok(response_body),
                 Err(err) => {
@@ -149,137 +435,653 @@ pub fn create_message(
                 }
             }
         }
-        _ => {
-            panic!("Invalid option")
+        message_type => Err(ManagerError::Validation(format!(
+            "{} messages can't be sent directly, use a composed type",
+            message_type.as_str()
+        ))),
+    }
+}
+
+fn required_body(message: &MessageRequest) -> Result<String, ManagerError> {
+    match &message.content.body {
+        Some(body) => Ok(body.clone()),
+        None => {
+            error!("Body is required for {} messages", message.message_type);
+            Err(ManagerError::Validation(format!(
+                "Body is required for {} messages",
+                message.message_type
+            )))
         }
-    };
+    }
 }
 
-pub fn get_user_mode(phone_number: &str) -> Result<u16, RedisError> {
-    let client = create_client().unwrap();
-    let mut con = client.get_connection().unwrap();
+fn set_header_and_footer(
+    request: &mut MessageBuilder,
+    message: &MessageRequest,
+    default_header: Option<String>,
+) -> Result<(), ManagerError> {
+    match &message.content.header {
+        Some(HeaderContent {
+            header_type,
+            text,
+            media,
+        }) => match header_type.as_str() {
+            "text" => {
+                let text = text.clone().ok_or_else(|| {
+                    ManagerError::Validation("Text header requires text".to_string())
+                })?;
+                request.header(text);
+            }
+            "image" | "video" | "document" => {
+                let media = media.as_ref().ok_or_else(|| {
+                    ManagerError::Validation("Media header requires media".to_string())
+                })?;
+                let media_type = MessageType::from_str(header_type)?;
+
+                media.validate(media_type)?;
+                request.media_header(media_type, media.to_media());
+            }
+            header_type => {
+                return Err(ManagerError::Validation(format!(
+                    "Header type {} is not supported",
+                    header_type
+                )))
+            }
+        },
+        None => {
+            if let Some(default_header) = default_header {
+                request.header(default_header);
+            }
+        }
+    }
+
+    if let Some(footer) = &message.content.footer {
+        request.footer(footer.clone());
+    }
+
+    Ok(())
+}
+
+// Conversations are kept per business number, a customer can write to more than one
+fn conversation(business_number: &str, phone_number: &str) -> String {
+    format!("{}:{}", business_number, phone_number)
+}
+
+// Conversation keys written before there were many business numbers, with the segments after
+// the prefix they had, like selected-mode:{phone_number} or incoming-messages:{phone_number}:{id}
+const LEGACY_CONVERSATION_KEYS: [(&str, usize); 4] = [
+    ("selected-mode", 1),
+    ("last-message", 1),
+    ("incoming-messages", 2),
+    ("outgoing-messages", 2),
+];
+const CONVERSATION_KEYS_MIGRATED: &str = "migrations:conversation-keys";
+
+// Key of a legacy conversation on the business number, None if the key already has one
+fn migrated_key(key: &str, prefix: &str, segments: usize, business_number: &str) -> Option<String> {
+    let rest = key.strip_prefix(prefix)?.strip_prefix(':')?;
+
+    if rest.split(':').count() != segments {
+        return None;
+    }
+
+    Some(format!("{}:{}:{}", prefix, business_number, rest))
+}
+
+// Legacy conversations belong to the default number, they are renamed once so customers keep
+// their mode and history. A key already written on the business number is never overwritten
+pub fn migrate_conversation_keys() -> Result<usize, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    let migrated: bool = con.exists(CONVERSATION_KEYS_MIGRATED)?;
+    if migrated {
+        return Ok(0);
+    }
+
+    let business_number = &config::get().phone_number_id;
+    let mut renamed = 0;
+
+    for (prefix, segments) in LEGACY_CONVERSATION_KEYS {
+        let keys: Vec<String> = con.scan_match(format!("{}:*", prefix))?.collect();
+
+        for key in keys {
+            if let Some(new_key) = migrated_key(&key, prefix, segments, business_number) {
+                let moved: bool = con.rename_nx(&key, &new_key)?;
+
+                if moved {
+                    renamed += 1;
+                } else {
+                    warn!("Couldnt migrate {}, {} already exists", key, new_key);
+                }
+            }
+        }
+    }
+
+    let _: () = con.set(CONVERSATION_KEYS_MIGRATED, renamed)?;
+
+    Ok(renamed)
+}
+
+pub fn get_user_mode(business_number: &str, phone_number: &str) -> Result<u16, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
 
-    println!("{}", format!("selected-mode:{}", phone_number));
+    let key = format!(
+        "selected-mode:{}",
+        conversation(business_number, phone_number)
+    );
+    debug!("Getting user mode from {}", key);
 
-    let mode: RedisResult<String> = con.hget(format!("selected-mode:{}", phone_number), "mode");
+    let mode: RedisResult<String> = con.hget(key, "mode");
 
-    println!("2222: {}", mode.as_ref().unwrap());
     if mode.is_err() {
         error!("Error obtaining mode: {}", mode.as_ref().unwrap_err());
         let is_nil = is_nil(mode.as_ref().unwrap_err());
         // Sets user mode to 0 in case its the first message
         return if is_nil {
-            set_user_mode(phone_number, "100");
+            set_user_mode(business_number, phone_number, "100")?;
             Ok(0)
         } else {
             Err(mode.unwrap_err())
         };
     }
 
-    println!("33333: {}", mode.as_ref().unwrap());
-
-    let parsed_mode = mode.unwrap().parse::<u16>().unwrap();
+    let mode = mode?;
+    let parsed_mode = match mode.parse::<u16>() {
+        Ok(parsed_mode) => parsed_mode,
+        Err(_) => {
+            return Err(RedisError::from((
+                ErrorKind::TypeError,
+                "Selected mode is not a number",
+                mode,
+            )))
+        }
+    };
 
-    println!("Parsed: {}", parsed_mode);
+    debug!("Selected mode: {}", parsed_mode);
 
     Ok(parsed_mode)
 }
 
-pub fn set_user_mode(phone_number: &str, mode: &str) -> Result<String, RedisError> {
-    let client = create_client().unwrap();
-    let mut con = client.get_connection().unwrap();
+pub fn set_user_mode(
+    business_number: &str,
+    phone_number: &str,
+    mode: &str,
+) -> Result<String, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
 
-    let mode: RedisResult<String> =
-        con.hset(format!("selected-mode:{}", phone_number), "mode", mode);
+    let mode: RedisResult<String> = con.hset(
+        format!(
+            "selected-mode:{}",
+            conversation(business_number, phone_number)
+        ),
+        "mode",
+        mode,
+    );
 
-    if mode.is_ok() {
-        Ok(mode.unwrap().clone())
-    } else {
-        Err(mode.unwrap_err())
-    }
+    mode
 }
 
 pub fn store_message(
     event: &impl Serialize,
+    business_number: &str,
     to: &String,
     message_id: &String,
     namespace: &str,
 ) -> Result<String, RedisError> {
-    let client = create_client().unwrap();
-    let mut con = client.get_connection().unwrap();
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
 
-    let json = serde_json::to_string(&event).unwrap();
-    trace!("JSON: {}", json);
-    let key = format!("{}:{}:{}", namespace, to, message_id);
+    if let Ok(json) = serde_json::to_string(&event) {
+        trace!("JSON: {}", json);
+    }
+    let key = format!(
+        "{}:{}:{}",
+        namespace,
+        conversation(business_number, to),
+        message_id
+    );
 
-    con.json_set(key, "$", &event)?;
+    con.json_set(&key, "$", &event)?;
 
-    Ok(format!("{}:{}:{}", namespace, to, message_id))
+    Ok(key)
 }
 
-pub fn get_destination_system(mode: u16) -> Result<Vec<String>, RedisError> {
-    let client = create_client().unwrap();
-    let mut con = client.get_connection().unwrap();
+pub fn add_status_history(status: &Status) -> Result<String, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
 
-    let mode_list = con.lrange(format!("mode-systems:{}", mode), 0, 100);
+    let key = format!("message-status:{}", status.id);
 
-    if mode_list.is_err() {
-        if is_nil(&mode_list.as_ref().unwrap_err()) {
-            return Ok(vec![]);
-        }
+    // A retried status is already on the history when a later step failed
+    let history: Vec<String> = con.lrange(&key, 0, -1)?;
+    let repeated = history
+        .iter()
+        .filter_map(|entry| serde_json::from_str::<Status>(entry).ok())
+        .any(|entry| entry.status == status.status && entry.timestamp == status.timestamp);
 
-        return Err(mode_list.unwrap_err());
+    if !repeated {
+        let _: () = con.rpush(&key, serde_json::to_string(status).unwrap())?;
     }
 
-    Ok(mode_list.unwrap())
+    Ok(key)
 }
 
-pub fn set_last_message(id: &str, phone_number: &str) -> Result<String, RedisError> {
-    let client = create_client().unwrap();
-    let mut con = client.get_connection().unwrap();
+pub fn get_status_history(message_id: &str) -> Result<Vec<Status>, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
 
-    let res: String = con
-        .set(format!("last-message:{}", phone_number), id)
-        .unwrap();
+    let history: Vec<String> = con.lrange(format!("message-status:{}", message_id), 0, -1)?;
 
-    Ok(res)
+    Ok(history
+        .iter()
+        .filter_map(|status| serde_json::from_str(status).ok())
+        .collect())
+}
+
+// Outgoing messages are stored under the receiver the caller sent, which isn't always the
+// wa_id meta uses on statuses, so the storage key is indexed by message id
+pub fn index_outgoing_message(message_id: &str, key: &str) -> Result<(), RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    con.set(format!("outgoing-message-keys:{}", message_id), key)
+}
+
+// Sets the current status on the stored outgoing message, returns None if the message wasn't sent by us
+pub fn set_outgoing_status(
+    business_number: &str,
+    status: &Status,
+) -> Result<Option<String>, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    // Messages sent before the index existed are looked up by the recipient meta reports
+    let indexed: Option<String> = con.get(format!("outgoing-message-keys:{}", status.id))?;
+    let key = indexed.unwrap_or(format!(
+        "outgoing-messages:{}:{}",
+        conversation(business_number, &status.recipient_id),
+        status.id
+    ));
+    let exists: bool = con.exists(&key)?;
+
+    if !exists {
+        return Ok(None);
+    }
+
+    let _: () = con.json_set(&key, "$.status", status)?;
+
+    Ok(Some(key))
+}
+
+// System id of the MessageRequest that created an outgoing message
+pub fn get_outgoing_system(register_id: &str) -> Result<Option<u8>, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    let res: Option<String> = con.json_get(register_id, "$.system_id")?;
+
+    Ok(res
+        .and_then(|json| serde_json::from_str::<Vec<u8>>(&json).ok())
+        .and_then(|ids| ids.first().copied()))
+}
+
+pub fn get_destination_system(mode: u16) -> Result<Vec<String>, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    match con.lrange(format!("mode-systems:{}", mode), 0, 100) {
+        Ok(mode_list) => Ok(mode_list),
+        Err(err) if is_nil(&err) => Ok(vec![]),
+        Err(err) => Err(err),
+    }
 }
 
-pub fn get_user_last_message(phone_number: &str) -> Result<String, RedisError> {
-    let client = create_client().unwrap();
-    let mut con = client.get_connection().unwrap();
+pub fn set_last_message(
+    business_number: &str,
+    id: &str,
+    phone_number: &str,
+) -> Result<String, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
 
-    let mut res: RedisResult<String> = con.get(format!("last-message:{}", phone_number));
+    let res: String = con.set(
+        format!(
+            "last-message:{}",
+            conversation(business_number, phone_number)
+        ),
+        id,
+    )?;
 
-    if res.is_err() {
-        let is_nil = is_nil(&res.as_ref().unwrap_err());
+    Ok(res)
+}
 
+pub fn get_user_last_message(
+    business_number: &str,
+    phone_number: &str,
+) -> Result<String, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    let res: RedisResult<String> = con.get(format!(
+        "last-message:{}",
+        conversation(business_number, phone_number)
+    ));
+
+    match res {
+        Ok(res) => Ok(res),
         // Check if it is phone numbers first message
-        if is_nil {
-            set_last_message("", phone_number);
+        Err(err) if is_nil(&err) => {
+            set_last_message(business_number, "", phone_number)?;
 
-            return Ok("".to_string());
+            Ok("".to_string())
         }
-        return Err(res.unwrap_err());
+        Err(err) => Err(err),
+    }
+}
+
+// Unix timestamp of the last message received from a phone number on a business number
+pub fn get_last_inbound_timestamp(
+    business_number: &str,
+    phone_number: &str,
+) -> Result<Option<i64>, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    let conversation = conversation(business_number, phone_number);
+    let message_id: Option<String> = con.get(format!("last-message:{}", conversation))?;
+
+    let message_id = match message_id {
+        Some(message_id) if !message_id.is_empty() => message_id,
+        _ => return Ok(None),
+    };
+
+    let res: Option<String> = con.json_get(
+        format!("incoming-messages:{}:{}", conversation, message_id),
+        "$.entry[0].changes[0].value.messages[0].timestamp",
+    )?;
+
+    Ok(res
+        .and_then(|json| serde_json::from_str::<Vec<String>>(&json).ok())
+        .and_then(|timestamps| timestamps.first().and_then(|t| t.parse::<i64>().ok())))
+}
+
+// Template sent instead of free-form messages outside the customer service window
+pub fn get_fallback_template(system_id: u8) -> Result<Option<TemplateMessage>, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    let (name, language): (Option<String>, Option<String>) = con.hget(
+        format!("fallback-template:{}", system_id),
+        &["name", "language"],
+    )?;
+
+    Ok(match (name, language) {
+        (Some(name), Some(language)) => Some(TemplateMessage {
+            name,
+            language,
+            components: vec![],
+        }),
+        _ => None,
+    })
+}
+
+pub fn get_cached_templates() -> Result<Option<Vec<TemplateDefinition>>, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    let res: Option<String> = con.get("message-templates")?;
+
+    Ok(res.and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+pub fn cache_templates(templates: &Vec<TemplateDefinition>) -> Result<String, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    // Approval state changes on meta side, so the list is refreshed every hour
+    let _: () = con.set_ex(
+        "message-templates",
+        serde_json::to_string(templates).unwrap(),
+        60 * 60,
+    )?;
+
+    Ok("message-templates".to_string())
+}
+
+pub fn clear_cached_templates() -> Result<(), RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    con.del("message-templates")
+}
+
+pub fn get_user_message(
+    business_number: &str,
+    message_id: String,
+    phone_number: &str,
+) -> Result<Event, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    let res: String = con.json_get(
+        format!(
+            "incoming-messages:{}:{}",
+            conversation(business_number, phone_number),
+            message_id
+        ),
+        ".",
+    )?;
+
+    serde_json::from_str(&res).map_err(|err| {
+        RedisError::from((
+            ErrorKind::TypeError,
+            "Stored message is not a valid event",
+            err.to_string(),
+        ))
+    })
+}
+
+pub fn get_media_id(hash: &str) -> Result<Option<String>, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    con.get(format!("media-ids:{}", hash))
+}
+
+pub fn set_media_id(hash: &str, media_id: &str) -> Result<String, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    // Meta keeps uploaded media for 30 days, expire the cached id a day earlier
+    let _: () = con.set_ex(format!("media-ids:{}", hash), media_id, 29 * 24 * 60 * 60)?;
+
+    Ok(format!("media-ids:{}", hash))
+}
+
+// Webhook work already done, keyed by namespace, like processed-messages:{wamid}
+pub const PROCESSED_MESSAGES: &str = "processed-messages";
+pub const PUBLISHED_MESSAGES: &str = "published-messages";
+pub const PROCESSED_STATUSES: &str = "processed-statuses";
+
+// A claim not released or marked as processed expires, so the work is retried if its consumer died
+const CLAIM_TTL_SECS: usize = 2 * 60;
+const CLAIM_PROCESSING: &str = "processing";
+
+pub enum Claim {
+    Claimed,
+    // Another consumer is processing it right now
+    Processing,
+    Processed,
+}
+
+pub fn is_processed(namespace: &str, id: &str) -> Result<bool, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    con.exists(format!("{}:{}", namespace, id))
+}
+
+// Takes the work with SET NX, so redeliveries of the same id are never processed at once
+pub fn claim(namespace: &str, id: &str) -> Result<Claim, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    let key = format!("{}:{}", namespace, id);
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(&key)
+        .arg(CLAIM_PROCESSING)
+        .arg("NX")
+        .arg("EX")
+        .arg(CLAIM_TTL_SECS)
+        .query(&mut con)?;
+
+    if claimed.is_some() {
+        return Ok(Claim::Claimed);
+    }
+
+    // A claim that expired right now is taken on the next retry
+    let state: Option<String> = con.get(&key)?;
+    Ok(match state.as_deref() {
+        Some(CLAIM_PROCESSING) | None => Claim::Processing,
+        Some(_) => Claim::Processed,
+    })
+}
+
+// Releases a claim whose work failed, so a retry can take it right away
+pub fn release_claim(namespace: &str, id: &str) -> Result<(), RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    con.del(format!("{}:{}", namespace, id))
+}
+
+// Only called once the work succeeded, so a failure or crash midway is retried
+pub fn mark_processed(namespace: &str, id: &str) -> Result<(), RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    // Meta retries failed deliveries for up to 7 days
+    con.set_ex(format!("{}:{}", namespace, id), 1, 7 * 24 * 60 * 60)
+}
+
+pub fn enqueue_webhook_event(body: &str) -> Result<String, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    con.xadd_maxlen(
+        WEBHOOK_STREAM,
+        StreamMaxlen::Approx(WEBHOOK_STREAM_MAXLEN),
+        "*",
+        &[("event", body)],
+    )
+}
+
+pub fn create_webhook_group() -> Result<(), RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    let res: RedisResult<()> = con.xgroup_create_mkstream(WEBHOOK_STREAM, WEBHOOK_GROUP, "0");
+
+    // Group was already created by another instance
+    match res {
+        Err(err) if err.code() == Some("BUSYGROUP") => Ok(()),
+        res => res,
     }
+}
+
+// Waits up to block_ms for events never delivered to any consumer of the group
+pub fn read_webhook_events(
+    consumer: &str,
+    count: usize,
+    block_ms: usize,
+) -> Result<Vec<StreamId>, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
 
-    Ok(res.unwrap())
+    let options = StreamReadOptions::default()
+        .group(WEBHOOK_GROUP, consumer)
+        .count(count)
+        .block(block_ms);
+
+    let reply: Option<StreamReadReply> = con.xread_options(&[WEBHOOK_STREAM], &[">"], &options)?;
+
+    Ok(reply
+        .map(|reply| reply.keys.into_iter().flat_map(|key| key.ids).collect())
+        .unwrap_or_default())
 }
 
-pub fn get_user_message(message_id: String, phone_number: &str) -> Result<Event, RedisError> {
-    let client = create_client().unwrap();
-    let mut con = client.get_connection().unwrap();
+// Acknowledged events are deleted, otherwise the stream would keep every event ever received
+pub fn ack_webhook_event(id: &str) -> Result<(), RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
 
-    let res: String = con
-        .json_get(
-            format!("incoming-messages:{}:{}", phone_number, message_id),
-            ".",
-        )
-        .unwrap();
+    let _: () = con.xack(WEBHOOK_STREAM, WEBHOOK_GROUP, &[id])?;
+    con.xdel(WEBHOOK_STREAM, &[id])
+}
 
-    let event: Event = serde_json::from_str(&res).unwrap();
+// Claims events delivered but not acknowledged for at least min_idle_ms, returning them with
+// the number of times each one was delivered before this claim
+pub fn claim_stale_webhook_events(
+    consumer: &str,
+    min_idle_ms: usize,
+    count: usize,
+) -> Result<Vec<(StreamId, usize)>, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    let pending: StreamPendingCountReply =
+        con.xpending_count(WEBHOOK_STREAM, WEBHOOK_GROUP, "-", "+", count)?;
+
+    let stale: Vec<_> = pending
+        .ids
+        .into_iter()
+        .filter(|pending| pending.last_delivered_ms >= min_idle_ms)
+        .collect();
+
+    if stale.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let ids: Vec<&str> = stale.iter().map(|pending| pending.id.as_str()).collect();
+    let claimed: redis::streams::StreamClaimReply =
+        con.xclaim(WEBHOOK_STREAM, WEBHOOK_GROUP, consumer, min_idle_ms, &ids)?;
+
+    Ok(claimed
+        .ids
+        .into_iter()
+        .filter_map(|event| {
+            stale
+                .iter()
+                .find(|pending| pending.id == event.id)
+                .map(|pending| (event.clone(), pending.times_delivered))
+        })
+        .collect())
+}
+
+// Moves an event that can't be processed to the dead letter stream and acknowledges it
+pub fn dead_letter_webhook_event(event: &StreamId, reason: &str) -> Result<String, RedisError> {
+    let client = create_client()?;
+    let mut con = client.get_connection()?;
+
+    let body: String = event.get("event").unwrap_or_default();
+    let id: String = con.xadd_maxlen(
+        WEBHOOK_DEAD_LETTER_STREAM,
+        StreamMaxlen::Approx(WEBHOOK_STREAM_MAXLEN),
+        "*",
+        &[
+            ("event", body.as_str()),
+            ("original_id", &event.id),
+            ("reason", reason),
+        ],
+    )?;
+
+    let _: () = con.xack(WEBHOOK_STREAM, WEBHOOK_GROUP, &[&event.id])?;
+    let _: () = con.xdel(WEBHOOK_STREAM, &[&event.id])?;
 
-    Ok(event)
+    Ok(id)
 }
 
 pub fn is_nil(error: &RedisError) -> bool {
@@ -289,3 +1091,62 @@ pub fn is_nil(error: &RedisError) -> bool {
         false
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_only_legacy_conversation_keys() {
+        let business_number = "110000391967238";
+
+        assert_eq!(
+            migrated_key(
+                "selected-mode:5491155550000",
+                "selected-mode",
+                1,
+                business_number
+            ),
+            Some("selected-mode:110000391967238:5491155550000".to_string())
+        );
+        assert_eq!(
+            migrated_key(
+                "incoming-messages:5491155550000:wamid.HBgLNTQ5MTE1NTU1MDAwMBUCABIYIDNBRkY=",
+                "incoming-messages",
+                2,
+                business_number
+            ),
+            Some(
+                "incoming-messages:110000391967238:5491155550000:wamid.HBgLNTQ5MTE1NTU1MDAwMBUCABIYIDNBRkY="
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            migrated_key(
+                "selected-mode:110000391967238:5491155550000",
+                "selected-mode",
+                1,
+                business_number
+            ),
+            None
+        );
+        assert_eq!(
+            migrated_key(
+                "outgoing-messages:110000391967238:5491155550000:wamid.HBgL",
+                "outgoing-messages",
+                2,
+                business_number
+            ),
+            None
+        );
+        assert_eq!(
+            migrated_key(
+                "selected-modes:5491155550000",
+                "selected-mode",
+                1,
+                business_number
+            ),
+            None
+        );
+    }
+}
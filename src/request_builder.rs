@@ -1,37 +1,210 @@
+use crate::config;
+use crate::error_manager::{unreadable_body, ManagerError};
 use crate::structs::webhooks;
 use log::{debug, error};
 use serde_derive::{Deserialize, Serialize};
-use std::error::Error;
-use std::time::Duration;
+use std::sync::OnceLock;
+use unicode_segmentation::UnicodeSegmentation;
 use ureq::Agent;
 
-// #[derive(Serialize, Deserialize, Clone)]
-// pub struct MessageRequest {
-//     pub system_id: u8,
-//     pub to: Vec<String>,
-//     #[serde(rename(serialize = "type"))]
-//     pub message_type: String,
-//     pub content: MessageContent,
-// }
+// Every graph api call goes through this agent, so connections to meta are reused
+pub fn graph_agent() -> &'static Agent {
+    static AGENT: OnceLock<Agent> = OnceLock::new();
+    AGENT.get_or_init(|| {
+        let config = config::get();
+
+        // Per read and write timeouts instead of a total one, media files can take a while
+        ureq::AgentBuilder::new()
+            .timeout_connect(config.connect_timeout)
+            .timeout_read(config.request_timeout)
+            .timeout_write(config.request_timeout)
+            .build()
+    })
+}
+
+fn messages_url(phone_number_id: &str) -> String {
+    format!("{}/{}/messages", config::get().graph_url(), phone_number_id)
+}
+
 #[derive(Serialize, Deserialize, Clone)]
+pub struct MessageRequest {
+    pub system_id: u8,
+    pub to: Vec<String>,
+    pub message_type: String,
+    pub content: MessageContent,
+    // wamid of the message being answered, shown as a quote on the user chat
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    // Name or phone number id of the business number to send from, defaults to the system one
+    #[serde(default)]
+    pub sender: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct MessageContent {
     pub body: Option<String>,
     pub list: Option<ListMessage>,
     pub buttons: Option<ButtonMessage>,
+    pub media: Option<MediaMessage>,
+    pub template: Option<TemplateMessage>,
+    pub header: Option<HeaderContent>,
+    pub footer: Option<String>,
+    pub cta_url: Option<CtaUrlMessage>,
+    pub location: Option<Location>,
+    // Contacts can be sent in the whatsapp format or as vCard 3.0 text
+    pub contacts: Option<Vec<webhooks::SharedContact>>,
+    pub vcards: Option<Vec<String>>,
+    pub reaction: Option<Reaction>,
 }
 
+// An empty emoji removes a previous reaction
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Reaction {
+    pub message_id: String,
+    #[serde(default)]
+    pub emoji: String,
+}
+
+// Button that opens the url, sent as an interactive cta_url message
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CtaUrlMessage {
+    pub display_text: String,
+    pub url: String,
+}
+
+// Header of interactive messages, text or an image, video or document media
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HeaderContent {
+    #[serde(rename = "type")]
+    pub header_type: String,
+    pub text: Option<String>,
+    pub media: Option<MediaMessage>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TemplateMessage {
+    pub name: String,
+    pub language: String,
+    #[serde(default)]
+    pub components: Vec<TemplateComponent>,
+}
+
+impl TemplateMessage {
+    pub fn to_template(&self) -> Template {
+        Template {
+            name: self.name.clone(),
+            language: Language {
+                code: self.language.clone(),
+            },
+            components: self.components.clone(),
+        }
+    }
+}
+
+// choices are shown in a single untitled section, sections allow grouping them by name
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ListMessage {
     pub title: String,
-    pub choices: Vec<String>,
+    // Text of the button that opens the list, up to 20 characters
+    #[serde(default)]
+    pub button: Option<String>,
+    #[serde(default)]
+    pub choices: Vec<ListChoice>,
+    pub sections: Option<Vec<ListSection>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ListSection {
+    pub title: String,
+    pub choices: Vec<ListChoice>,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ListChoice {
+    pub id: String,
+    pub value: String,
+    pub description: Option<String>,
+}
+
+// Limits enforced by meta on list messages
+pub const LIST_MAX_ROWS: usize = 10;
+pub const LIST_MAX_SECTIONS: usize = 10;
+pub const LIST_ROW_TITLE_MAX: usize = 24;
+pub const LIST_ROW_DESCRIPTION_MAX: usize = 72;
+pub const LIST_ROW_ID_MAX: usize = 200;
+pub const LIST_SECTION_TITLE_MAX: usize = 24;
+pub const LIST_BUTTON_MAX: usize = 20;
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ButtonMessage {
     pub title: String,
     pub choices: Vec<String>,
 }
 
+// Media sent either by an id obtained from the media endpoint or by a public link.
+// mime_type and file_size are optional hints used only for validation, they are not sent to meta.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct MediaMessage {
+    pub id: Option<String>,
+    pub link: Option<String>,
+    pub caption: Option<String>,
+    pub filename: Option<String>,
+    pub mime_type: Option<String>,
+    pub file_size: Option<u64>,
+}
+
+impl MediaMessage {
+    pub fn validate(&self, media_type: MessageType) -> Result<(), ManagerError> {
+        let limits = match media_type.media_limits() {
+            Some(limits) => limits,
+            None => {
+                return Err(ManagerError::Validation(format!(
+                    "{} is not a media message type",
+                    media_type.as_str()
+                )))
+            }
+        };
+
+        if let Some(mime_type) = &self.mime_type {
+            if !limits.mime_types.contains(&mime_type.as_str()) {
+                return Err(ManagerError::Validation(format!(
+                    "Mime type {} is not supported for {} messages, allowed types: {}",
+                    mime_type,
+                    media_type.as_str(),
+                    limits.mime_types.join(", ")
+                )));
+            }
+        }
+
+        if let Some(file_size) = self.file_size {
+            if file_size > limits.max_size {
+                return Err(ManagerError::Validation(format!(
+                    "File size {} exceeds the {} bytes limit for {} messages",
+                    file_size,
+                    limits.max_size,
+                    media_type.as_str()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn to_media(&self) -> Media {
+        Media {
+            id: self.id.clone(),
+            link: self.link.clone(),
+            caption: self.caption.clone(),
+            filename: self.filename.clone(),
+        }
+    }
+}
+
+pub struct MediaLimits {
+    pub mime_types: &'static [&'static str],
+    pub max_size: u64,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct WhatsappRequest {
     messaging_product: String,
@@ -41,6 +214,235 @@ pub struct WhatsappRequest {
     message_type: String,
     text: Option<webhooks::Text>,
     interactive: Option<InteractiveDefinition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<Media>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    document: Option<Media>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audio: Option<Media>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    video: Option<Media>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sticker: Option<Media>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    template: Option<Template>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<Location>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contacts: Option<Vec<webhooks::SharedContact>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reaction: Option<Reaction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context: Option<ReplyContext>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReplyContext {
+    message_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+}
+
+impl Location {
+    pub fn validate(&self) -> Result<(), ManagerError> {
+        if !(-90.0..=90.0).contains(&self.latitude) || !(-180.0..=180.0).contains(&self.longitude) {
+            return Err(ManagerError::Validation(format!(
+                "Coordinates {}, {} are out of range",
+                self.latitude, self.longitude
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Template {
+    name: String,
+    language: Language,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    components: Vec<TemplateComponent>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Language {
+    code: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TemplateComponent {
+    // header, body or button
+    #[serde(rename = "type")]
+    pub component_type: String,
+    // quick_reply or url, only for buttons
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_type: Option<String>,
+    // Position of the button the parameters belong to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<String>,
+    #[serde(default)]
+    pub parameters: Vec<TemplateParameter>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TemplateParameter {
+    Text { text: String },
+    Currency { currency: Currency },
+    DateTime { date_time: DateTime },
+    Image { image: Media },
+    Document { document: Media },
+    Video { video: Media },
+    Payload { payload: String },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Currency {
+    pub fallback_value: String,
+    pub code: String,
+    pub amount_1000: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DateTime {
+    pub fallback_value: String,
+}
+
+impl TemplateParameter {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TemplateParameter::Text { .. } => "text",
+            TemplateParameter::Currency { .. } => "currency",
+            TemplateParameter::DateTime { .. } => "date_time",
+            TemplateParameter::Image { .. } => "image",
+            TemplateParameter::Document { .. } => "document",
+            TemplateParameter::Video { .. } => "video",
+            TemplateParameter::Payload { .. } => "payload",
+        }
+    }
+}
+
+impl Template {
+    // Checks parameter counts and types for each component
+    pub fn validate(&self) -> Result<(), ManagerError> {
+        if self.name.is_empty() || self.language.code.is_empty() {
+            return Err(ManagerError::Validation(
+                "Template name and language are required".to_string(),
+            ));
+        }
+
+        let mut has_header = false;
+        let mut button_indexes: Vec<u8> = vec![];
+
+        for component in &self.components {
+            let parameter_types: Vec<&str> =
+                component.parameters.iter().map(|p| p.as_str()).collect();
+
+            match component.component_type.as_str() {
+                "header" => {
+                    if has_header {
+                        return Err(ManagerError::Validation(
+                            "Template allows only one header component".to_string(),
+                        ));
+                    }
+                    has_header = true;
+
+                    if parameter_types.len() > 1 {
+                        return Err(ManagerError::Validation(format!(
+                            "Template header allows one parameter, received {}",
+                            parameter_types.len()
+                        )));
+                    }
+
+                    if let Some(parameter_type) = parameter_types
+                        .iter()
+                        .find(|t| !["text", "image", "document", "video"].contains(t))
+                    {
+                        return Err(ManagerError::Validation(format!(
+                            "Template header doesn't allow {} parameters",
+                            parameter_type
+                        )));
+                    }
+                }
+                "body" => {
+                    if let Some(parameter_type) = parameter_types
+                        .iter()
+                        .find(|t| !["text", "currency", "date_time"].contains(t))
+                    {
+                        return Err(ManagerError::Validation(format!(
+                            "Template body doesn't allow {} parameters",
+                            parameter_type
+                        )));
+                    }
+                }
+                "button" => {
+                    let expected_type = match component.sub_type.as_deref() {
+                        Some("quick_reply") => "payload",
+                        Some("url") => "text",
+                        _ => {
+                            return Err(ManagerError::Validation(
+                                "Template button sub_type must be quick_reply or url".to_string(),
+                            ))
+                        }
+                    };
+
+                    let index = match component.index.as_ref().map(|i| i.parse::<u8>()) {
+                        Some(Ok(index)) if index < 10 => index,
+                        _ => {
+                            return Err(ManagerError::Validation(
+                                "Template button index must be a number from 0 to 9".to_string(),
+                            ))
+                        }
+                    };
+
+                    if button_indexes.contains(&index) {
+                        return Err(ManagerError::Validation(format!(
+                            "Template button index {} is repeated",
+                            index
+                        )));
+                    }
+                    button_indexes.push(index);
+
+                    if parameter_types != [expected_type] {
+                        return Err(ManagerError::Validation(format!(
+                            "Template {} button {} requires exactly one {} parameter",
+                            component.sub_type.as_ref().unwrap(),
+                            index,
+                            expected_type
+                        )));
+                    }
+                }
+                component_type => {
+                    return Err(ManagerError::Validation(format!(
+                        "Template component {} is not supported",
+                        component_type
+                    )))
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Media {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    caption: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filename: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -49,16 +451,135 @@ pub struct InteractiveDefinition {
     interactive_type: String,
     body: Body,
     action: Action,
-    header: Header,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    header: Option<Header>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    footer: Option<Footer>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Header {
     #[serde(rename(serialize = "type"))]
     header_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<Media>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    video: Option<Media>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    document: Option<Media>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Footer {
     text: String,
 }
 
+// Limits enforced by meta on interactive messages
+pub const HEADER_TEXT_MAX: usize = 60;
+pub const FOOTER_TEXT_MAX: usize = 60;
+pub const CTA_DISPLAY_TEXT_MAX: usize = 20;
+
+const INTERACTIVE_TYPE_NOT_SET: &str =
+    "interactive type is not set, please call the message_type method with a composed type";
+
+// Extended_Pictographic code points of the unicode emoji data, grouped in ranges
+const PICTOGRAPHIC: [(u32, u32); 40] = [
+    (0x00A9, 0x00A9),
+    (0x00AE, 0x00AE),
+    (0x203C, 0x203C),
+    (0x2049, 0x2049),
+    (0x2122, 0x2122),
+    (0x2139, 0x2139),
+    (0x2194, 0x2199),
+    (0x21A9, 0x21AA),
+    (0x231A, 0x231B),
+    (0x2328, 0x2328),
+    (0x2388, 0x2388),
+    (0x23CF, 0x23CF),
+    (0x23E9, 0x23F3),
+    (0x23F8, 0x23FA),
+    (0x24C2, 0x24C2),
+    (0x25AA, 0x25AB),
+    (0x25B6, 0x25B6),
+    (0x25C0, 0x25C0),
+    (0x25FB, 0x25FE),
+    (0x2600, 0x2605),
+    (0x2607, 0x2612),
+    (0x2614, 0x2685),
+    (0x2690, 0x2705),
+    (0x2708, 0x2712),
+    (0x2714, 0x2714),
+    (0x2716, 0x2716),
+    (0x271D, 0x271D),
+    (0x2721, 0x2721),
+    (0x2728, 0x2728),
+    (0x2733, 0x2734),
+    (0x2744, 0x2747),
+    (0x274C, 0x2767),
+    (0x2795, 0x27BF),
+    (0x2934, 0x2935),
+    (0x2B05, 0x2B07),
+    (0x2B1B, 0x2B1C),
+    (0x2B50, 0x2B55),
+    (0x3030, 0x303D),
+    (0x3297, 0x3299),
+    (0x1F000, 0x1FFFD),
+];
+
+fn is_pictographic(c: char) -> bool {
+    PICTOGRAPHIC
+        .iter()
+        .any(|(start, end)| (*start..=*end).contains(&(c as u32)))
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+// Characters that only modify the emoji they follow: zero width joiner, variation selectors,
+// the enclosing keycap and the tags of subdivision flags
+fn is_emoji_modifier(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200D}' | '\u{FE0E}' | '\u{FE0F}' | '\u{20E3}' | '\u{E0020}'..='\u{E007F}'
+    )
+}
+
+fn is_emoji(grapheme: &str) -> bool {
+    let chars: Vec<char> = grapheme.chars().collect();
+
+    match chars.as_slice() {
+        // Flags are a pair of regional indicators
+        [first, second] if is_regional_indicator(*first) && is_regional_indicator(*second) => true,
+        // Keycaps like 1️⃣ start with the plain character
+        [key, rest @ ..] if key.is_ascii_digit() || *key == '#' || *key == '*' => {
+            rest.last() == Some(&'\u{20E3}') && rest.iter().all(|c| is_emoji_modifier(*c))
+        }
+        // A lone regional indicator is only half a flag
+        [first, rest @ ..] => {
+            is_pictographic(*first)
+                && !is_regional_indicator(*first)
+                && rest
+                    .iter()
+                    .all(|c| is_pictographic(*c) || is_emoji_modifier(*c))
+        }
+        [] => false,
+    }
+}
+
+// Empty removes a reaction, anything else must be a single emoji
+fn is_reaction_emoji(emoji: &str) -> bool {
+    let mut graphemes = emoji.graphemes(true);
+
+    match (graphemes.next(), graphemes.next()) {
+        (None, _) => true,
+        (Some(grapheme), None) => is_emoji(grapheme),
+        _ => false,
+    }
+}
+
 impl Default for InteractiveDefinition {
     fn default() -> Self {
         Self {
@@ -73,11 +594,11 @@ impl Default for InteractiveDefinition {
                     title: "".to_string(),
                     rows: vec![],
                 }]),
+                name: None,
+                parameters: None,
             },
-            header: Header {
-                header_type: "text".to_string(),
-                text: "".to_string(),
-            },
+            header: None,
+            footer: None,
         }
     }
 }
@@ -89,9 +610,23 @@ pub struct Body {
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Action {
+    #[serde(skip_serializing_if = "Option::is_none")]
     buttons: Option<Vec<Button>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     button: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     sections: Option<Vec<Section>>,
+    // Only for cta_url messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parameters: Option<CtaUrlParameters>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CtaUrlParameters {
+    display_text: String,
+    url: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -104,6 +639,8 @@ pub struct Section {
 pub struct Row {
     title: String,
     id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -122,13 +659,31 @@ pub struct Reply {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct MessageBuilder {
     request: WhatsappRequest,
+    // Business number sending the message, the configured default when not set
+    #[serde(skip)]
+    phone_number_id: Option<String>,
+    // Misuses of the builder methods, returned by validate
+    #[serde(skip)]
+    errors: Vec<String>,
 }
 
+#[derive(Clone, Copy, PartialEq)]
 pub enum MessageType {
     Text,
     Interactive,
     InteractiveButton,
     InteractiveList,
+    InteractiveCtaUrl,
+    InteractiveLocationRequest,
+    Image,
+    Document,
+    Audio,
+    Video,
+    Sticker,
+    Template,
+    Location,
+    Contacts,
+    Reaction,
 }
 
 impl MessageType {
@@ -138,25 +693,167 @@ impl MessageType {
             MessageType::Interactive => "interactive",
             MessageType::InteractiveButton => "button",
             MessageType::InteractiveList => "list",
+            MessageType::InteractiveCtaUrl => "cta_url",
+            MessageType::InteractiveLocationRequest => "location_request_message",
+            MessageType::Image => "image",
+            MessageType::Document => "document",
+            MessageType::Audio => "audio",
+            MessageType::Video => "video",
+            MessageType::Sticker => "sticker",
+            MessageType::Template => "template",
+            MessageType::Location => "location",
+            MessageType::Contacts => "contacts",
+            MessageType::Reaction => "reaction",
         }
     }
 
-    pub fn from_str(message_type: &str) -> MessageType {
-        match message_type {
+    // Supported mime types and max file size as documented by meta for each media type
+    pub fn media_limits(&self) -> Option<MediaLimits> {
+        match self {
+            MessageType::Image => Some(MediaLimits {
+                mime_types: &["image/jpeg", "image/png"],
+                max_size: 5 * 1024 * 1024,
+            }),
+            MessageType::Document => Some(MediaLimits {
+                mime_types: &[
+                    "text/plain",
+                    "application/pdf",
+                    "application/vnd.ms-powerpoint",
+                    "application/msword",
+                    "application/vnd.ms-excel",
+                    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+                    "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+                    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+                ],
+                max_size: 100 * 1024 * 1024,
+            }),
+            MessageType::Audio => Some(MediaLimits {
+                mime_types: &[
+                    "audio/aac",
+                    "audio/mp4",
+                    "audio/mpeg",
+                    "audio/amr",
+                    "audio/ogg",
+                ],
+                max_size: 16 * 1024 * 1024,
+            }),
+            MessageType::Video => Some(MediaLimits {
+                mime_types: &["video/mp4", "video/3gpp"],
+                max_size: 16 * 1024 * 1024,
+            }),
+            // Animated stickers allow up to 500KB, static ones only 100KB
+            MessageType::Sticker => Some(MediaLimits {
+                mime_types: &["image/webp"],
+                max_size: 500 * 1024,
+            }),
+            _ => None,
+        }
+    }
+
+    // Media type a file would be sent as, based on its mime type
+    pub fn from_mime_type(mime_type: &str) -> Option<MessageType> {
+        [
+            MessageType::Image,
+            MessageType::Document,
+            MessageType::Audio,
+            MessageType::Video,
+            MessageType::Sticker,
+        ]
+        .into_iter()
+        .find(|media_type| {
+            media_type
+                .media_limits()
+                .is_some_and(|limits| limits.mime_types.contains(&mime_type))
+        })
+    }
+
+    pub fn is_media(&self) -> bool {
+        self.media_limits().is_some()
+    }
+
+    pub fn from_str(message_type: &str) -> Result<MessageType, ManagerError> {
+        let message_type = match message_type {
             "text" => MessageType::Text,
             "interactive" => MessageType::Interactive,
             "button" => MessageType::InteractiveButton,
             "list" => MessageType::InteractiveList,
+            "cta_url" => MessageType::InteractiveCtaUrl,
+            "location_request_message" => MessageType::InteractiveLocationRequest,
+            "image" => MessageType::Image,
+            "document" => MessageType::Document,
+            "audio" => MessageType::Audio,
+            "video" => MessageType::Video,
+            "sticker" => MessageType::Sticker,
+            "template" => MessageType::Template,
+            "location" => MessageType::Location,
+            "contacts" => MessageType::Contacts,
+            "reaction" => MessageType::Reaction,
             _ => {
-                error!(
-                    "{}",
-                    format!("Message type {} was not found", String::from(message_type)).as_str()
-                );
-                panic!(
-                    "{}",
-                    format!("Message type {} was not found", String::from(message_type)).as_str()
-                )
+                return Err(ManagerError::Validation(format!(
+                    "Message type {} was not found",
+                    message_type
+                )))
             }
+        };
+
+        Ok(message_type)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ReadRequest {
+    messaging_product: String,
+    status: String,
+    message_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    typing_indicator: Option<TypingIndicator>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct TypingIndicator {
+    #[serde(rename = "type")]
+    indicator_type: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ReadResponse {
+    success: bool,
+}
+
+// Marks an inbound message and the previous ones as read, the typing indicator lasts
+// until the next message is sent or 25 seconds
+pub fn mark_as_read(
+    phone_number_id: &str,
+    message_id: &str,
+    typing: bool,
+) -> Result<(), ManagerError> {
+    let request = ReadRequest {
+        messaging_product: "whatsapp".to_string(),
+        status: "read".to_string(),
+        message_id: message_id.to_string(),
+        typing_indicator: typing.then(|| TypingIndicator {
+            indicator_type: "text".to_string(),
+        }),
+    };
+
+    let response_body = graph_agent()
+        .post(messages_url(phone_number_id).as_str())
+        .set("Authorization", config::get().authorization().as_str())
+        .send_json(ureq::json!(&request))?
+        .into_string()
+        .map_err(unreadable_body)?;
+
+    match serde_json::from_str::<ReadResponse>(&response_body) {
+        Ok(response) if response.success => Ok(()),
+        _ => {
+            error!(
+                "Couldnt mark message {} as read: {}",
+                message_id, response_body
+            );
+            Err(ManagerError::MetaUnavailable(format!(
+                "Couldnt mark message {} as read",
+                message_id
+            )))
         }
     }
 }
@@ -184,37 +881,44 @@ impl MessageBuilder {
         MessageBuilder::default()
     }
 
-    pub fn execute(&self) -> Result<MessageResponse, Box<dyn Error>> {
+    pub fn execute(&self) -> Result<MessageResponse, ManagerError> {
+        self.validate()?;
+
         debug!("{}", ureq::json!(&self.request));
-        let resp = ureq::post("https://graph.facebook.com/v15.0/110000391967238/messages")
-            .set(
-                "Authorization",
-                format!("Bearer {}", std::env::var("META_TOKEN").unwrap()).as_str(),
+        let response_body = graph_agent()
+            .post(
+                messages_url(
+                    self.phone_number_id
+                        .as_deref()
+                        .unwrap_or(&config::get().phone_number_id),
+                )
+                .as_str(),
             )
+            .set("Authorization", config::get().authorization().as_str())
             .send_json(ureq::json!(&self.request))?
-            .into_string();
-
-        println!("{:?}", resp);
-
-        match resp {
-            Ok(response_body) => {
-                let parsed_response: Result<MessageResponse, _> =
-                    serde_json::from_str(response_body.as_str());
-
-                if parsed_response.is_err() {
-                    error!("{}", format!("Couldnt parse element: {}", response_body));
-                    panic!("{}", format!("Couldnt parse element: {}", response_body))
-                }
+            .into_string()
+            .map_err(unreadable_body)?;
 
-                Ok(parsed_response.unwrap())
-            }
-            Err(err) => {
-                error!("{}", format!("{}", err.to_string()));
-                panic!("{}", format!("{}", err.to_string()))
+        match serde_json::from_str::<MessageResponse>(response_body.as_str()) {
+            Ok(response) => Ok(response),
+            Err(_) => {
+                error!("Couldnt parse element: {}", response_body);
+                Err(ManagerError::MetaUnavailable(format!(
+                    "Couldnt parse meta response: {}",
+                    response_body
+                )))
             }
         }
     }
 
+    // Wrong type combinations are kept and returned by validate, so a request can't crash the service
+    fn reject(&mut self, message: impl Into<String>) -> &mut MessageBuilder {
+        let message = message.into();
+        error!("{}", message);
+        self.errors.push(message);
+        self
+    }
+
     pub fn message_type(
         &mut self,
         message_type: MessageType,
@@ -222,9 +926,13 @@ impl MessageBuilder {
     ) -> &mut MessageBuilder {
         // Check for primary types
         match &message_type {
-            MessageType::InteractiveButton | MessageType::InteractiveList => {
-                error!("Secondary types are not allowed, please use either text or interactive");
-                panic!("Secondary types are not allowed, please use either text or interactive")
+            MessageType::InteractiveButton
+            | MessageType::InteractiveList
+            | MessageType::InteractiveCtaUrl
+            | MessageType::InteractiveLocationRequest => {
+                return self.reject(
+                    "Secondary types are not allowed, please use either text or interactive",
+                );
             }
             _ => {}
         }
@@ -232,11 +940,13 @@ impl MessageBuilder {
         self.request.message_type = message_type.as_str().to_string();
 
         // Set secondary type if provided
-        if composed_type.is_some() && message_type.as_str() == MessageType::Interactive.as_str() {
-            self.request.interactive = Some(InteractiveDefinition {
-                interactive_type: composed_type.unwrap().as_str().to_string(),
-                ..Default::default()
-            })
+        if let Some(composed_type) = composed_type {
+            if message_type.as_str() == MessageType::Interactive.as_str() {
+                self.request.interactive = Some(InteractiveDefinition {
+                    interactive_type: composed_type.as_str().to_string(),
+                    ..Default::default()
+                })
+            }
         }
 
         self
@@ -245,56 +955,467 @@ impl MessageBuilder {
     pub fn body(&mut self, body: String) -> &mut MessageBuilder {
         // Check if message type is already set
         if self.request.message_type == "" {
-            error!("primary type is not set, please call the message_type method and set a value");
-            panic!("primary type is not set, please call the message_type method and set a value")
+            return self.reject(
+                "primary type is not set, please call the message_type method and set a value",
+            );
         }
 
-        match MessageType::from_str(&self.request.message_type) {
+        let message_type = match MessageType::from_str(&self.request.message_type) {
+            Ok(message_type) => message_type,
+            Err(err) => return self.reject(err.to_string()),
+        };
+
+        match message_type {
             MessageType::Text => {
                 self.request.text = Some(webhooks::Text { body });
             }
             MessageType::Interactive
             | MessageType::InteractiveButton
-            | MessageType::InteractiveList => {
-                let mut clone = self.request.interactive.clone();
-                clone.as_mut().unwrap().body.text = body;
+            | MessageType::InteractiveList
+            | MessageType::InteractiveCtaUrl
+            | MessageType::InteractiveLocationRequest => match self.request.interactive.as_mut() {
+                Some(interactive) => interactive.body.text = body,
+                None => return self.reject(INTERACTIVE_TYPE_NOT_SET),
+            },
+            MessageType::Image
+            | MessageType::Document
+            | MessageType::Audio
+            | MessageType::Video
+            | MessageType::Sticker => {
+                return self.reject("media messages doesn't allow body, use caption instead");
+            }
+            MessageType::Template => {
+                return self
+                    .reject("template messages doesn't allow body, use body component parameters");
+            }
+            MessageType::Location => {
+                return self
+                    .reject("location messages doesn't allow body, use name and address instead");
+            }
+            MessageType::Contacts => {
+                return self.reject("contacts messages doesn't allow body");
+            }
+            MessageType::Reaction => {
+                return self.reject("reaction messages doesn't allow body");
+            }
+        }
+
+        self
+    }
+
+    pub fn header(&mut self, header: String) -> &mut MessageBuilder {
+        // Check if message type is already set
+        if self.request.message_type == "" {
+            return self.reject(
+                "primary type is not set, please call the message_type method and set a value",
+            );
+        }
+
+        let message_type = match MessageType::from_str(&self.request.message_type) {
+            Ok(message_type) => message_type,
+            Err(err) => return self.reject(err.to_string()),
+        };
+
+        match message_type {
+            MessageType::Text
+            | MessageType::Image
+            | MessageType::Document
+            | MessageType::Audio
+            | MessageType::Video
+            | MessageType::Sticker
+            | MessageType::Template
+            | MessageType::Location
+            | MessageType::Contacts
+            | MessageType::Reaction => {
+                return self.reject(format!(
+                    "{} messages doesn't allow header",
+                    self.request.message_type
+                ));
+            }
+            MessageType::Interactive
+            | MessageType::InteractiveButton
+            | MessageType::InteractiveList
+            | MessageType::InteractiveCtaUrl
+            | MessageType::InteractiveLocationRequest => match self.request.interactive.as_mut() {
+                Some(interactive) => {
+                    interactive.header = Some(Header {
+                        header_type: "text".to_string(),
+                        text: Some(header),
+                        image: None,
+                        video: None,
+                        document: None,
+                    })
+                }
+                None => return self.reject(INTERACTIVE_TYPE_NOT_SET),
+            },
+        }
 
-                self.request.interactive = clone;
+        self
+    }
+
+    pub fn media_header(&mut self, media_type: MessageType, media: Media) -> &mut MessageBuilder {
+        if self.request.interactive.is_none() {
+            return self.reject("Only interactive messages allow media header");
+        }
+
+        let mut header = Header {
+            header_type: media_type.as_str().to_string(),
+            text: None,
+            image: None,
+            video: None,
+            document: None,
+        };
+
+        match media_type {
+            MessageType::Image => header.image = Some(media),
+            MessageType::Video => header.video = Some(media),
+            MessageType::Document => header.document = Some(media),
+            _ => {
+                return self.reject(format!(
+                    "{} media is not allowed as header",
+                    media_type.as_str()
+                ));
             }
         }
 
+        if let Some(interactive) = self.request.interactive.as_mut() {
+            interactive.header = Some(header);
+        }
+
         self
     }
 
-    pub fn main_button_content(&mut self, button_name: String) -> &mut MessageBuilder {
+    pub fn footer(&mut self, footer: String) -> &mut MessageBuilder {
+        if self.request.interactive.is_none() {
+            return self.reject("Only interactive messages allow footer");
+        }
 
-        self.request.interactive.as_mut().unwrap().action.button = Some(button_name);
+        if let Some(interactive) = self.request.interactive.as_mut() {
+            interactive.footer = Some(Footer { text: footer });
+        }
 
         self
     }
 
-    pub fn header(&mut self, header: String) -> &mut MessageBuilder {
+    pub fn cta_url(&mut self, display_text: String, url: String) -> &mut MessageBuilder {
+        let interactive_type = self
+            .request
+            .interactive
+            .as_ref()
+            .map(|interactive| interactive.interactive_type.as_str());
+
+        if interactive_type != Some(MessageType::InteractiveCtaUrl.as_str()) {
+            return self
+                .reject("To set a call to action url, message type must be InteractiveCtaUrl");
+        }
+
+        if let Some(interactive) = self.request.interactive.as_mut() {
+            interactive.action = Action {
+                buttons: None,
+                button: None,
+                sections: None,
+                name: Some(MessageType::InteractiveCtaUrl.as_str().to_string()),
+                parameters: Some(CtaUrlParameters { display_text, url }),
+            };
+        }
+
+        self
+    }
+
+    pub fn request_location(&mut self) -> &mut MessageBuilder {
+        let interactive_type = self
+            .request
+            .interactive
+            .as_ref()
+            .map(|interactive| interactive.interactive_type.as_str());
+
+        if interactive_type != Some(MessageType::InteractiveLocationRequest.as_str()) {
+            return self
+                .reject("To request a location, message type must be InteractiveLocationRequest");
+        }
+
+        if let Some(interactive) = self.request.interactive.as_mut() {
+            interactive.action = Action {
+                buttons: None,
+                button: None,
+                sections: None,
+                name: Some("send_location".to_string()),
+                parameters: None,
+            };
+        }
+
+        self
+    }
+
+    pub fn location(&mut self, location: Location) -> &mut MessageBuilder {
+        if self.request.message_type != MessageType::Location.as_str() {
+            return self.reject("To set a location, message type must be Location");
+        }
+
+        self.request.location = Some(location);
+        self
+    }
+
+    pub fn contacts(&mut self, contacts: Vec<webhooks::SharedContact>) -> &mut MessageBuilder {
+        if self.request.message_type != MessageType::Contacts.as_str() {
+            return self.reject("To set contacts, message type must be Contacts");
+        }
+
+        self.request.contacts = Some(contacts);
+        self
+    }
+
+    pub fn reaction(&mut self, message_id: String, emoji: String) -> &mut MessageBuilder {
+        if self.request.message_type != MessageType::Reaction.as_str() {
+            return self.reject("To set a reaction, message type must be Reaction");
+        }
+
+        self.request.reaction = Some(Reaction { message_id, emoji });
+        self
+    }
+
+    pub fn media(&mut self, media: Media) -> &mut MessageBuilder {
         // Check if message type is already set
-        if self.request.message_type == "" {
-            error!("primary type is not set, please call the message_type method and set a value");
-            panic!("primary type is not set, please call the message_type method and set a value")
+        if self.request.message_type.is_empty() {
+            return self.reject(
+                "primary type is not set, please call the message_type method and set a value",
+            );
         }
 
-        match MessageType::from_str(&self.request.message_type) {
-            MessageType::Text => {
-                error!("text messages doesn't allow header");
-                panic!("text messages doesn't allow header")
+        let message_type = match MessageType::from_str(&self.request.message_type) {
+            Ok(message_type) => message_type,
+            Err(err) => return self.reject(err.to_string()),
+        };
+
+        match message_type {
+            MessageType::Image => self.request.image = Some(media),
+            MessageType::Document => self.request.document = Some(media),
+            MessageType::Audio => self.request.audio = Some(media),
+            MessageType::Video => self.request.video = Some(media),
+            MessageType::Sticker => self.request.sticker = Some(media),
+            _ => {
+                return self.reject(format!(
+                    "{} messages doesn't allow media",
+                    self.request.message_type
+                ));
             }
-            MessageType::Interactive
-            | MessageType::InteractiveButton
-            | MessageType::InteractiveList => {
-                let mut clone = self.request.interactive.clone();
-                clone.as_mut().unwrap().header.text = header;
+        }
+
+        self
+    }
+
+    pub fn template(&mut self, template: Template) -> &mut MessageBuilder {
+        if self.request.message_type != MessageType::Template.as_str() {
+            return self.reject("To set a template, message type must be Template");
+        }
+
+        self.request.template = Some(template);
+        self
+    }
+
+    // Checks the request against meta rules before sending it
+    pub fn validate(&self) -> Result<(), ManagerError> {
+        if !self.errors.is_empty() {
+            return Err(ManagerError::Validation(self.errors.join(", ")));
+        }
+
+        let message_type = MessageType::from_str(&self.request.message_type)?;
+
+        if let Some(interactive) = &self.request.interactive {
+            if let Some(header) = &interactive.header {
+                let text = header.text.as_deref().unwrap_or_default();
+                if text.chars().count() > HEADER_TEXT_MAX {
+                    return Err(ManagerError::Validation(format!(
+                        "Header text has {} characters, max {}",
+                        text.chars().count(),
+                        HEADER_TEXT_MAX
+                    )));
+                }
+
+                if header.header_type != "text"
+                    && interactive.interactive_type == MessageType::InteractiveList.as_str()
+                {
+                    return Err(ManagerError::Validation(
+                        "List messages only allow text header".to_string(),
+                    ));
+                }
+            }
+
+            if let Some(footer) = &interactive.footer {
+                if footer.text.chars().count() > FOOTER_TEXT_MAX {
+                    return Err(ManagerError::Validation(format!(
+                        "Footer text has {} characters, max {}",
+                        footer.text.chars().count(),
+                        FOOTER_TEXT_MAX
+                    )));
+                }
+            }
+
+            if interactive.interactive_type == MessageType::InteractiveList.as_str() {
+                self.validate_list()?;
+            }
+
+            if interactive.interactive_type == MessageType::InteractiveCtaUrl.as_str() {
+                self.validate_cta_url()?;
+            }
+
+            if interactive.interactive_type == MessageType::InteractiveLocationRequest.as_str() {
+                if interactive.body.text.is_empty() {
+                    return Err(ManagerError::Validation(
+                        "Location request message requires a body".to_string(),
+                    ));
+                }
+
+                if interactive.header.is_some() {
+                    return Err(ManagerError::Validation(
+                        "Location request messages doesn't allow header".to_string(),
+                    ));
+                }
+            }
+        }
+
+        if message_type == MessageType::Template {
+            match &self.request.template {
+                Some(template) => template.validate()?,
+                None => {
+                    return Err(ManagerError::Validation(
+                        "template message has no template".to_string(),
+                    ))
+                }
+            }
+        }
+
+        if message_type == MessageType::Location {
+            match &self.request.location {
+                Some(location) => location.validate()?,
+                None => {
+                    return Err(ManagerError::Validation(
+                        "location message has no location".to_string(),
+                    ))
+                }
+            }
+        }
+
+        if let Some(context) = &self.request.context {
+            if context.message_id.is_empty() {
+                return Err(ManagerError::Validation(
+                    "Reply requires the id of the message to reply to".to_string(),
+                ));
+            }
+
+            if message_type == MessageType::Reaction {
+                return Err(ManagerError::Validation(
+                    "reaction messages can't be sent as a reply".to_string(),
+                ));
+            }
+        }
+
+        if message_type == MessageType::Reaction {
+            match &self.request.reaction {
+                Some(reaction) if reaction.message_id.is_empty() => {
+                    return Err(ManagerError::Validation(
+                        "Reaction requires the id of the message to react to".to_string(),
+                    ))
+                }
+                Some(reaction) if !is_reaction_emoji(&reaction.emoji) => {
+                    return Err(ManagerError::Validation(format!(
+                        "Reaction '{}' is not a single emoji",
+                        reaction.emoji
+                    )))
+                }
+                Some(_) => {}
+                None => {
+                    return Err(ManagerError::Validation(
+                        "reaction message has no reaction".to_string(),
+                    ))
+                }
+            }
+        }
+
+        if message_type == MessageType::Contacts {
+            let contacts = self.request.contacts.as_deref().unwrap_or_default();
+            if contacts.is_empty() {
+                return Err(ManagerError::Validation(
+                    "contacts message has no contacts".to_string(),
+                ));
+            }
+
+            for contact in contacts {
+                let name = &contact.name;
+                if name.formatted_name.is_empty() {
+                    return Err(ManagerError::Validation(
+                        "Contact formatted name is required".to_string(),
+                    ));
+                }
 
-                self.request.interactive = clone;
+                // Meta requires at least one name part besides the formatted name
+                let parts = [
+                    &name.first_name,
+                    &name.last_name,
+                    &name.middle_name,
+                    &name.prefix,
+                    &name.suffix,
+                ];
+                if parts.iter().all(|part| part.is_none()) {
+                    return Err(ManagerError::Validation(format!(
+                        "Contact {} requires a first, last, middle name, prefix or suffix",
+                        name.formatted_name
+                    )));
+                }
             }
         }
 
+        if message_type.is_media() {
+            let media = match message_type {
+                MessageType::Image => &self.request.image,
+                MessageType::Document => &self.request.document,
+                MessageType::Audio => &self.request.audio,
+                MessageType::Video => &self.request.video,
+                _ => &self.request.sticker,
+            };
+
+            let media = match media {
+                Some(media) => media,
+                None => {
+                    return Err(ManagerError::Validation(format!(
+                        "{} message has no media",
+                        self.request.message_type
+                    )))
+                }
+            };
+
+            if media.id.is_some() == media.link.is_some() {
+                return Err(ManagerError::Validation(
+                    "Media must be referenced by either id or link".to_string(),
+                ));
+            }
+
+            if media.caption.is_some()
+                && (message_type == MessageType::Audio || message_type == MessageType::Sticker)
+            {
+                return Err(ManagerError::Validation(format!(
+                    "{} messages doesn't allow caption",
+                    self.request.message_type
+                )));
+            }
+
+            if media.filename.is_some() && message_type != MessageType::Document {
+                return Err(ManagerError::Validation(
+                    "Only document messages allow filename".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn reply_to(&mut self, message_id: String) -> &mut MessageBuilder {
+        self.request.context = Some(ReplyContext { message_id });
+        self
+    }
+
+    pub fn from(&mut self, phone_number_id: String) -> &mut MessageBuilder {
+        self.phone_number_id = Some(phone_number_id);
         self
     }
 
@@ -310,12 +1431,17 @@ impl MessageBuilder {
     ) -> &mut MessageBuilder {
         let mut copy = self.request.clone();
         if self.request.message_type == "text" {
-            error!("Text message type doesnt allow actions");
-            panic!("Text message type doesnt allow actions")
+            return self.reject("Text message type doesnt allow actions");
         }
 
-        match MessageType::from_str(&self.request.interactive.as_ref().unwrap().interactive_type) {
-            MessageType::InteractiveButton => {
+        let interactive_type = match self.request.interactive.as_ref() {
+            Some(interactive) => MessageType::from_str(&interactive.interactive_type),
+            None => return self.reject(INTERACTIVE_TYPE_NOT_SET),
+        };
+
+        match interactive_type {
+            Err(err) => return self.reject(err.to_string()),
+            Ok(MessageType::InteractiveButton) => {
                 let default = format!("{}-id", button_content.to_lowercase().replace(" ", "-"));
                 let button_id_str = button_id.unwrap_or(default.as_str());
                 let button = Button {
@@ -326,18 +1452,17 @@ impl MessageBuilder {
                     },
                 };
                 // self.request.interactive.as_ref().unwrap().action.buttons.as_ref().unwrap().push(button);
-                copy.interactive
-                    .as_mut()
-                    .unwrap()
-                    .action
-                    .buttons
-                    .as_mut()
-                    .unwrap()
-                    .push(button);
-            }
-            MessageType::InteractiveList => {
-                error!("Invalid method for message type, use add_list_button method instead");
-                panic!("Invalid method for message type, use add_list_button method instead")
+                if let Some(interactive) = copy.interactive.as_mut() {
+                    interactive
+                        .action
+                        .buttons
+                        .get_or_insert(vec![])
+                        .push(button);
+                }
+            }
+            Ok(MessageType::InteractiveList) => {
+                return self
+                    .reject("Invalid method for message type, use add_list_button method instead");
             }
             _ => {}
         }
@@ -348,12 +1473,20 @@ impl MessageBuilder {
     }
 
     pub fn set_button_title(&mut self, button_title: &str) -> &mut MessageBuilder {
-        if &self.request.message_type != MessageType::InteractiveList.as_str() {
-            error!("To set button title, message type must be InteractiveList");
-            panic!("To set button title, message type must be InteractiveList");
+        let interactive_type = self
+            .request
+            .interactive
+            .as_ref()
+            .map(|interactive| interactive.interactive_type.as_str());
+
+        if interactive_type != Some(MessageType::InteractiveList.as_str()) {
+            return self.reject("To set button title, message type must be InteractiveList");
+        }
+
+        if let Some(interactive) = self.request.interactive.as_mut() {
+            interactive.action.button = Some(button_title.to_string());
         }
 
-        self.request.clone().interactive.unwrap().action.button = Some(button_title.to_string());
         self
     }
 
@@ -361,57 +1494,209 @@ impl MessageBuilder {
         &mut self,
         button_content: &str,
         button_id: Option<&str>,
-        button_name: &str,
+        description: Option<&str>,
+        section_title: &str,
     ) -> &mut MessageBuilder {
-
         let mut copy = self.request.clone();
         if self.request.message_type == "text" {
-            error!("Text message type doesnt allow actions");
-            panic!("Text message type doesnt allow actions")
+            return self.reject("Text message type doesnt allow actions");
         }
 
-        match MessageType::from_str(&self.request.interactive.as_ref().unwrap().interactive_type) {
-            MessageType::InteractiveList => {
+        let interactive_type = match self.request.interactive.as_ref() {
+            Some(interactive) => MessageType::from_str(&interactive.interactive_type),
+            None => return self.reject(INTERACTIVE_TYPE_NOT_SET),
+        };
+
+        match interactive_type {
+            Err(err) => return self.reject(err.to_string()),
+            Ok(MessageType::InteractiveList) => {
                 let default = format!("{}-id", button_content.to_lowercase().replace(" ", "-"));
                 let button_id_str = button_id.unwrap_or(default.as_str());
 
-                // Check if section exists, if not creates a section with an empty list of buttons
-                if copy.interactive.as_ref().unwrap().action.sections.is_none() {
+                let row = Row {
+                    id: button_id_str.to_string(),
+                    title: button_content.to_string(),
+                    description: description.map(|description| description.to_string()),
+                };
 
-                    let section = Some(vec![Section{
-                        title: "".to_string(),
-                        rows: vec![],
-                    }]);
+                let sections = match copy.interactive.as_mut() {
+                    Some(interactive) => interactive.action.sections.get_or_insert(vec![]),
+                    None => return self.reject(INTERACTIVE_TYPE_NOT_SET),
+                };
 
+                // Drop the empty placeholder section created by default
+                sections
+                    .retain(|section| !section.rows.is_empty() || section.title == section_title);
 
-                    copy.interactive
-                        .as_mut()
-                        .unwrap()
-                        .action
-                        .sections = section;
+                // Rows are grouped on the section with the same title, creating it if needed
+                match sections
+                    .iter_mut()
+                    .find(|section| section.title == section_title)
+                {
+                    Some(section) => section.rows.push(row),
+                    None => sections.push(Section {
+                        title: section_title.to_string(),
+                        rows: vec![row],
+                    }),
                 }
+            }
+            Ok(MessageType::InteractiveButton) => {
+                return self.reject(
+                    "Invalid method for message type, use add_reply_button method instead",
+                );
+            }
+            _ => {}
+        }
 
-                let row = Row{
-                    id: button_id_str.to_string(),
-                    title: button_content.to_string(),
-                };
+        self.request = copy;
+
+        self
+    }
+
+    fn validate_list(&self) -> Result<(), ManagerError> {
+        let interactive = match self.request.interactive.as_ref() {
+            Some(interactive) => interactive,
+            None => {
+                return Err(ManagerError::Validation(
+                    INTERACTIVE_TYPE_NOT_SET.to_string(),
+                ))
+            }
+        };
+        let sections = interactive.action.sections.as_deref().unwrap_or_default();
+        let button = interactive.action.button.as_deref().unwrap_or_default();
+
+        if button.is_empty() || button.chars().count() > LIST_BUTTON_MAX {
+            return Err(ManagerError::Validation(format!(
+                "List button text '{}' must have between 1 and {} characters",
+                button, LIST_BUTTON_MAX
+            )));
+        }
+
+        let rows: Vec<&Row> = sections
+            .iter()
+            .flat_map(|section| section.rows.iter())
+            .collect();
+        if rows.is_empty() || rows.len() > LIST_MAX_ROWS {
+            return Err(ManagerError::Validation(format!(
+                "List must have between 1 and {} rows, received {}",
+                LIST_MAX_ROWS,
+                rows.len()
+            )));
+        }
 
+        if sections.len() > LIST_MAX_SECTIONS {
+            return Err(ManagerError::Validation(format!(
+                "List allows up to {} sections, received {}",
+                LIST_MAX_SECTIONS,
+                sections.len()
+            )));
+        }
 
-                &copy.interactive.as_mut().unwrap().action.sections.as_mut().unwrap()[0].rows.push(row);
+        for section in sections {
+            if sections.len() > 1 && section.title.is_empty() {
+                return Err(ManagerError::Validation(
+                    "List sections must have a title when there is more than one".to_string(),
+                ));
+            }
 
+            if section.title.chars().count() > LIST_SECTION_TITLE_MAX {
+                return Err(ManagerError::Validation(format!(
+                    "List section '{}' title has {} characters, max {}",
+                    section.title,
+                    section.title.chars().count(),
+                    LIST_SECTION_TITLE_MAX
+                )));
+            }
+        }
+
+        for (position, row) in rows.iter().enumerate() {
+            if row.title.is_empty() || row.title.chars().count() > LIST_ROW_TITLE_MAX {
+                return Err(ManagerError::Validation(format!(
+                    "List row '{}' title has {} characters, it must have between 1 and {}",
+                    row.title,
+                    row.title.chars().count(),
+                    LIST_ROW_TITLE_MAX
+                )));
+            }
 
+            if let Some(description) = &row.description {
+                if description.chars().count() > LIST_ROW_DESCRIPTION_MAX {
+                    return Err(ManagerError::Validation(format!(
+                        "List row '{}' description has {} characters, max {}",
+                        row.title,
+                        description.chars().count(),
+                        LIST_ROW_DESCRIPTION_MAX
+                    )));
+                }
+            }
 
+            if row.id.is_empty() || row.id.chars().count() > LIST_ROW_ID_MAX {
+                return Err(ManagerError::Validation(format!(
+                    "List row '{}' id must have between 1 and {} characters",
+                    row.title, LIST_ROW_ID_MAX
+                )));
             }
-            MessageType::InteractiveButton => {
-                error!("Invalid method for message type, use add_list_button method instead");
-                panic!("Invalid method for message type, use add_list_button method instead")
+
+            if rows[..position].iter().any(|other| other.id == row.id) {
+                return Err(ManagerError::Validation(format!(
+                    "List row id '{}' is repeated",
+                    row.id
+                )));
             }
-            _ => {}
         }
 
-        self.request = copy;
+        Ok(())
+    }
 
-        self
+    fn validate_cta_url(&self) -> Result<(), ManagerError> {
+        let interactive = match self.request.interactive.as_ref() {
+            Some(interactive) => interactive,
+            None => {
+                return Err(ManagerError::Validation(
+                    INTERACTIVE_TYPE_NOT_SET.to_string(),
+                ))
+            }
+        };
+        let parameters = match &interactive.action.parameters {
+            Some(parameters) => parameters,
+            None => {
+                return Err(ManagerError::Validation(
+                    "Call to action message has no url".to_string(),
+                ))
+            }
+        };
+
+        if interactive.body.text.is_empty() {
+            return Err(ManagerError::Validation(
+                "Call to action message requires a body".to_string(),
+            ));
+        }
+
+        let display_text = &parameters.display_text;
+        if display_text.is_empty() || display_text.chars().count() > CTA_DISPLAY_TEXT_MAX {
+            return Err(ManagerError::Validation(format!(
+                "Call to action text '{}' must have between 1 and {} characters",
+                display_text, CTA_DISPLAY_TEXT_MAX
+            )));
+        }
+
+        // Meta only opens absolute https urls
+        let host = parameters
+            .url
+            .strip_prefix("https://")
+            .map(|rest| rest.split(['/', '?', '#']).next().unwrap_or_default());
+
+        match host {
+            Some(host) if !host.is_empty() && !parameters.url.contains(char::is_whitespace) => {}
+            _ => {
+                return Err(ManagerError::Validation(format!(
+                    "Call to action url '{}' is not a valid url",
+                    parameters.url
+                )))
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -425,7 +1710,321 @@ impl Default for MessageBuilder {
                 message_type: "".to_string(),
                 text: None,
                 interactive: None,
+                image: None,
+                document: None,
+                audio: None,
+                video: None,
+                sticker: None,
+                template: None,
+                location: None,
+                contacts: None,
+                reaction: None,
+                context: None,
             },
+            phone_number_id: None,
+            errors: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list() -> MessageBuilder {
+        let mut builder = MessageBuilder::new();
+        builder
+            .message_type(MessageType::Interactive, Some(MessageType::InteractiveList))
+            .body("Elegí un repuesto".to_string())
+            .set_button_title("Ver repuestos");
+        builder
+    }
+
+    fn validation_message(builder: &MessageBuilder) -> String {
+        match builder.validate() {
+            Err(ManagerError::Validation(message)) => message,
+            Err(err) => panic!("Expected a validation error, found {:?}", err),
+            Ok(_) => panic!("Expected a validation error"),
+        }
+    }
+
+    #[test]
+    fn accepts_list_with_sections_and_descriptions() {
+        let mut builder = list();
+        builder
+            .add_list_button(
+                "Filtro de aceite",
+                Some("oil"),
+                Some("Para motores nafteros"),
+                "Motor",
+            )
+            .add_list_button("Pastillas", Some("pads"), None, "Frenos")
+            .add_list_button("Discos", Some("discs"), Some("Par delantero"), "Frenos");
+
+        assert!(builder.validate().is_ok());
+
+        let sections = builder
+            .request
+            .interactive
+            .unwrap()
+            .action
+            .sections
+            .unwrap();
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[1].rows.len(), 2);
+    }
+
+    #[test]
+    fn rejects_list_over_row_limit() {
+        let mut builder = list();
+        for row in 0..=LIST_MAX_ROWS {
+            builder.add_list_button(&format!("Repuesto {}", row), None, None, "Motor");
+        }
+
+        assert_eq!(
+            validation_message(&builder),
+            "List must have between 1 and 10 rows, received 11"
+        );
+        assert_eq!(
+            validation_message(&list()),
+            "List must have between 1 and 10 rows, received 0"
+        );
+    }
+
+    #[test]
+    fn rejects_list_text_over_limits() {
+        let mut builder = list();
+        builder.add_list_button(
+            &"a".repeat(LIST_ROW_TITLE_MAX + 1),
+            Some("long"),
+            None,
+            "Motor",
+        );
+        assert!(validation_message(&builder).contains("title has 25 characters"));
+
+        let mut builder = list();
+        builder.add_list_button(
+            "Filtro",
+            None,
+            Some(&"a".repeat(LIST_ROW_DESCRIPTION_MAX + 1)),
+            "Motor",
+        );
+        assert!(validation_message(&builder).contains("description has 73 characters"));
+
+        let mut builder = list();
+        builder.add_list_button(
+            "Filtro",
+            None,
+            None,
+            &"a".repeat(LIST_SECTION_TITLE_MAX + 1),
+        );
+        assert!(validation_message(&builder).contains("title has 25 characters, max 24"));
+
+        // Limits count characters, not bytes
+        let mut builder = list();
+        builder.add_list_button(&"ñ".repeat(LIST_ROW_TITLE_MAX), None, None, "Motor");
+        assert!(builder.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_untitled_and_repeated_list_rows() {
+        let mut builder = list();
+        builder
+            .add_list_button("Filtro", Some("filter"), None, "Motor")
+            .add_list_button("Pastillas", Some("pads"), None, "");
+        assert_eq!(
+            validation_message(&builder),
+            "List sections must have a title when there is more than one"
+        );
+
+        let mut builder = list();
+        builder
+            .add_list_button("Filtro", Some("part"), None, "Motor")
+            .add_list_button("Pastillas", Some("part"), None, "Motor");
+        assert_eq!(
+            validation_message(&builder),
+            "List row id 'part' is repeated"
+        );
+    }
+
+    fn template(components: &str) -> Template {
+        serde_json::from_str(&format!(
+            r#"{{"name": "cotizacion", "language": {{"code": "es_AR"}}, "components": {}}}"#,
+            components
+        ))
+        .unwrap()
+    }
+
+    fn template_error(components: &str) -> String {
+        match template(components).validate() {
+            Err(ManagerError::Validation(message)) => message,
+            Err(err) => panic!("Expected a validation error, found {:?}", err),
+            Ok(_) => panic!("Expected a validation error"),
+        }
+    }
+
+    #[test]
+    fn accepts_template_parameters() {
+        let components = r#"[
+            {"type": "header", "parameters": [{"type": "image", "image": {"link": "https://example.com/auto.jpg"}}]},
+            {"type": "body", "parameters": [
+                {"type": "text", "text": "Juan"},
+                {"type": "currency", "currency": {"fallback_value": "$100", "code": "ARS", "amount_1000": 100000}},
+                {"type": "date_time", "date_time": {"fallback_value": "1 de mayo"}}
+            ]},
+            {"type": "button", "sub_type": "quick_reply", "index": "0", "parameters": [{"type": "payload", "payload": "SI"}]},
+            {"type": "button", "sub_type": "url", "index": "1", "parameters": [{"type": "text", "text": "1234"}]}
+        ]"#;
+
+        assert!(template(components).validate().is_ok());
+        assert!(template("[]").validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_wrong_template_parameter_counts() {
+        assert_eq!(
+            template_error(
+                r#"[{"type": "header", "parameters": [{"type": "text", "text": "A"}, {"type": "text", "text": "B"}]}]"#
+            ),
+            "Template header allows one parameter, received 2"
+        );
+        assert_eq!(
+            template_error(
+                r#"[{"type": "button", "sub_type": "quick_reply", "index": "0", "parameters": []}]"#
+            ),
+            "Template quick_reply button 0 requires exactly one payload parameter"
+        );
+        assert_eq!(
+            template_error(
+                r#"[{"type": "button", "sub_type": "url", "index": "0", "parameters": [{"type": "text", "text": "A"}, {"type": "text", "text": "B"}]}]"#
+            ),
+            "Template url button 0 requires exactly one text parameter"
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_template_components() {
+        assert_eq!(
+            template_error(r#"[{"type": "header"}, {"type": "header"}]"#),
+            "Template allows only one header component"
+        );
+        assert_eq!(
+            template_error(
+                r#"[{"type": "body", "parameters": [{"type": "payload", "payload": "SI"}]}]"#
+            ),
+            "Template body doesn't allow payload parameters"
+        );
+        assert_eq!(
+            template_error(
+                r#"[{"type": "button", "sub_type": "url", "index": "10", "parameters": [{"type": "text", "text": "A"}]}]"#
+            ),
+            "Template button index must be a number from 0 to 9"
+        );
+        assert_eq!(
+            template_error(
+                r#"[{"type": "button", "sub_type": "quick_reply", "index": "0", "parameters": [{"type": "payload", "payload": "SI"}]},
+                    {"type": "button", "sub_type": "quick_reply", "index": "0", "parameters": [{"type": "payload", "payload": "NO"}]}]"#
+            ),
+            "Template button index 0 is repeated"
+        );
+        assert_eq!(
+            template_error(r#"[{"type": "footer"}]"#),
+            "Template component footer is not supported"
+        );
+    }
+
+    fn cta_url(url: &str) -> MessageBuilder {
+        let mut builder = MessageBuilder::new();
+        builder
+            .message_type(
+                MessageType::Interactive,
+                Some(MessageType::InteractiveCtaUrl),
+            )
+            .body("Tu cotización está lista".to_string())
+            .cta_url("Ver cotización".to_string(), url.to_string());
+        builder
+    }
+
+    #[test]
+    fn accepts_https_cta_url() {
+        assert!(cta_url("https://example.com/cotizaciones/1234?v=2")
+            .validate()
+            .is_ok());
+        assert!(cta_url("https://example.com").validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_non_https_or_malformed_cta_url() {
+        for url in [
+            "http://example.com/cotizaciones/1234",
+            "ftp://example.com",
+            "example.com/cotizaciones",
+            "https://",
+            "https:///cotizaciones",
+            "https://example.com/cotizacion 1234",
+            "",
+        ] {
+            assert_eq!(
+                validation_message(&cta_url(url)),
+                format!("Call to action url '{}' is not a valid url", url)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_cta_url_display_text_and_body() {
+        let mut builder = MessageBuilder::new();
+        builder
+            .message_type(
+                MessageType::Interactive,
+                Some(MessageType::InteractiveCtaUrl),
+            )
+            .body("Tu cotización está lista".to_string())
+            .cta_url(
+                "a".repeat(CTA_DISPLAY_TEXT_MAX + 1),
+                "https://example.com".to_string(),
+            );
+        assert!(validation_message(&builder).contains("must have between 1 and 20 characters"));
+
+        let mut builder = MessageBuilder::new();
+        builder
+            .message_type(
+                MessageType::Interactive,
+                Some(MessageType::InteractiveCtaUrl),
+            )
+            .cta_url(
+                "Ver cotización".to_string(),
+                "https://example.com".to_string(),
+            );
+        assert_eq!(
+            validation_message(&builder),
+            "Call to action message requires a body"
+        );
+    }
+
+    #[test]
+    fn accepts_single_emoji_reactions() {
+        for emoji in [
+            "",
+            "👍",
+            "❤️",
+            "❤",
+            "👍🏽",
+            "👨‍👩‍👧‍👦",
+            "🏳️‍🌈",
+            "🇦🇷",
+            "1️⃣",
+            "©️",
+            "🏴󠁧󠁢󠁳󠁣󠁴󠁿",
+        ] {
+            assert!(is_reaction_emoji(emoji), "{}", emoji);
+        }
+    }
+
+    #[test]
+    fn rejects_text_reactions() {
+        for emoji in ["a", "ñ", "é", "中", "ok", "👍👍", "1", "🇦", "€", "\u{200D}"] {
+            assert!(!is_reaction_emoji(emoji), "{}", emoji);
         }
     }
 }
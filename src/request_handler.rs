@@ -1,37 +1,88 @@
+use crate::blob_store;
+use crate::config;
+use crate::error_manager::ManagerError;
+use crate::media;
 use crate::redis::{
-    create_message, get_destination_system, get_user_last_message, get_user_message, get_user_mode,
-    log_message, publish_message, set_last_message, set_user_mode, store_message,
+    add_status_history, cache_templates, claim, clear_cached_templates, create_message,
+    get_cached_templates, get_destination_system, get_fallback_template,
+    get_last_inbound_timestamp, get_media_id, get_outgoing_system, get_status_history,
+    get_user_last_message, get_user_message, get_user_mode, index_outgoing_message, is_processed,
+    log_message, mark_processed, publish_message, publish_status, release_claim, set_last_message,
+    set_media_id, set_outgoing_status, set_user_mode, store_message, Claim, PROCESSED_MESSAGES,
+    PROCESSED_STATUSES, PUBLISHED_MESSAGES,
 };
-use crate::request_builder::{MessageResponse};
-use crate::structs::webhooks::Event;
-use crate::structs::{MessageLog, ModifiedReference, StandardResponse};
-use actix_web::cookie::time::macros::offset;
-use actix_web::cookie::time::OffsetDateTime;
+use crate::request_builder::{
+    mark_as_read, MessageContent, MessageRequest, MessageType, TemplateMessage,
+};
+use crate::structs::webhooks::{Event, MessageKind, Status};
+use crate::structs::{
+    MessageLog, MessageStatus, ModifiedReference, ResponseError, StandardResponse,
+    StatusNotification,
+};
+use crate::templates;
+use crate::templates::TemplateDefinition;
+use crate::vcard;
 use actix_web::HttpResponse;
 use log::{debug, error, info, trace};
-use redis::RedisError;
 use serde::de::Unexpected::Str;
-use std::error::Error;
 use std::fmt::format;
-use std::time::{SystemTime, UNIX_EPOCH};
-use fizzy_commons::shared_structs::{MessageContent, MessageRequest};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub fn send_message(message: MessageRequest) -> Result<StandardResponse, StandardResponse> {
+pub fn send_message(mut message: MessageRequest) -> Result<StandardResponse, StandardResponse> {
     let mut response: StandardResponse = StandardResponse::new();
-    let mut errors = vec![];
+    let mut errors: Vec<ResponseError> = vec![];
     let mut references = vec![];
 
+    // Business number the message is sent from
+    let sender = match config::get().sender(message.sender.as_deref(), message.system_id) {
+        Ok(sender) => sender.to_string(),
+        Err(err) => {
+            error!("{}", err);
+            return Err(StandardResponse::error(ManagerError::Validation(err)));
+        }
+    };
+    message.sender = Some(sender.clone());
+
     // Iterate over receiver
-    info!("Sending message: {}", serde_json::to_string_pretty(&message).unwrap());
+    info!(
+        "Sending message: {}",
+        serde_json::to_string_pretty(&message).unwrap()
+    );
     for receiver in &message.to {
+        let outgoing = match check_service_window(&message, &sender, receiver) {
+            Ok(outgoing) => outgoing,
+            Err(err) => {
+                error!("{}", err);
+                errors.push(err.into());
+                continue;
+            }
+        };
+
+        if let Err(err) = check_template(&outgoing) {
+            error!("{}", err);
+            errors.push(err.into());
+            continue;
+        }
+
         // Sends the message though whatsapp API
         info!("Creating message");
-        let created_message = create_message(&message, receiver.to_string());
+        let created_message = create_message(&outgoing, receiver.to_string());
 
         match created_message {
             Ok(message_response) => {
                 // Add whatsapp id to references
-                let id = &message_response.messages[0].id;
+                let id = match message_response.messages.first() {
+                    Some(message) => &message.id,
+                    None => {
+                        let err = ManagerError::MetaUnavailable(format!(
+                            "Meta answered the message to {} without its id",
+                            receiver
+                        ));
+                        error!("{}", err);
+                        errors.push(err.into());
+                        continue;
+                    }
+                };
                 references.push(ModifiedReference {
                     system: "WHATSAPP".to_string(),
                     reference: id.to_string(),
@@ -40,14 +91,25 @@ pub fn send_message(message: MessageRequest) -> Result<StandardResponse, Standar
 
                 // Store message
                 info!("Storing message");
-                let store_res = store_message(&message, receiver, id, "outgoing-messages");
+                let store_res =
+                    store_message(&outgoing, &sender, receiver, id, "outgoing-messages");
 
                 match store_res {
                     Ok(storage_id) => {
+                        // Statuses can't be linked to the message without it
+                        if let Err(err) = index_outgoing_message(id, &storage_id) {
+                            error!("Couldnt index message {}: {}", id, err);
+                            errors.push(err.into());
+                        }
+
                         //Creates log
-                        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+                        let timestamp = match unix_now() {
                             Ok(n) => n.as_millis().to_string(),
-                            Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+                            Err(err) => {
+                                error!("{}", err);
+                                errors.push(err.into());
+                                continue;
+                            }
                         };
 
                         let log = MessageLog {
@@ -57,14 +119,27 @@ pub fn send_message(message: MessageRequest) -> Result<StandardResponse, Standar
                             origin_system: "1".to_string(),
                             origin: "OUTGOING".to_string(), //OUTGOING or INCOMING
                             register_id: storage_id.clone(),
+                            media_reference: None,
+                            message_type: None,
+                            location: None,
+                            vcards: None,
+                            reaction: None,
+                            context: None,
+                            business_phone_number_id: Some(sender.clone()),
                         };
 
                         // Publish message
                         info!("Publishing message");
-                        let publish_res = publish_message(&log, receiver);
+                        if let Err(err) = publish_message(&log, receiver) {
+                            error!("Couldnt publish message {}: {}", id, err);
+                            errors.push(err.into());
+                        }
 
                         info!("Logging message");
-                        log_message(&log);
+                        if let Err(err) = log_message(&log) {
+                            error!("Couldnt log message {}: {}", id, err);
+                            errors.push(err.into());
+                        }
 
                         references.push(ModifiedReference {
                             system: "REDIS".to_string(),
@@ -72,14 +147,14 @@ pub fn send_message(message: MessageRequest) -> Result<StandardResponse, Standar
                         });
                     }
                     Err(err) => {
-                        errors.push(format!("{}", err));
                         error!("{}", err);
+                        errors.push(err.into());
                     }
                 }
             }
             Err(err) => {
-                errors.push(format!("{}", err));
                 error!("{}", err);
+                errors.push(err.into());
             }
         }
     }
@@ -94,61 +169,585 @@ pub fn send_message(message: MessageRequest) -> Result<StandardResponse, Standar
     };
 }
 
+// Time since the unix epoch, the system clock is only before it when it is misconfigured
+fn unix_now() -> Result<Duration, ManagerError> {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_err(|err| {
+        ManagerError::Internal(format!("System clock is before the unix epoch: {}", err))
+    })
+}
+
+// Meta only allows free-form messages within 24 hours of the customer last message
+const SERVICE_WINDOW_SECS: i64 = 24 * 60 * 60;
+
+// Returns the message to send to the receiver, replacing free-form messages outside the
+// service window by the system fallback template when there is one
+fn check_service_window(
+    message: &MessageRequest,
+    sender: &str,
+    receiver: &str,
+) -> Result<MessageRequest, ManagerError> {
+    if MessageType::from_str(&message.message_type)? == MessageType::Template {
+        return Ok(message.clone());
+    }
+
+    let last_inbound = match get_last_inbound_timestamp(sender, receiver) {
+        Ok(last_inbound) => last_inbound,
+        Err(err) => {
+            // Let meta decide if the window can't be checked
+            error!("Couldnt obtain last message of {}: {}", receiver, err);
+            return Ok(message.clone());
+        }
+    };
+
+    let now = unix_now()?.as_secs() as i64;
+
+    apply_service_window(message, receiver, last_inbound, now, || {
+        get_fallback_template(message.system_id).map_err(ManagerError::from)
+    })
+}
+
+// The fallback template is only looked up when the message is outside the window
+fn apply_service_window(
+    message: &MessageRequest,
+    receiver: &str,
+    last_inbound: Option<i64>,
+    now: i64,
+    fallback_template: impl FnOnce() -> Result<Option<TemplateMessage>, ManagerError>,
+) -> Result<MessageRequest, ManagerError> {
+    if let Some(last_inbound) = last_inbound {
+        if now - last_inbound < SERVICE_WINDOW_SECS {
+            return Ok(message.clone());
+        }
+    }
+
+    match fallback_template()? {
+        Some(template) => {
+            info!(
+                "{} is outside the service window, sending template {}",
+                receiver, template.name
+            );
+            Ok(MessageRequest {
+                system_id: message.system_id,
+                to: vec![receiver.to_string()],
+                message_type: MessageType::Template.as_str().to_string(),
+                content: MessageContent {
+                    template: Some(template),
+                    ..Default::default()
+                },
+                reply_to: message.reply_to.clone(),
+                sender: message.sender.clone(),
+            })
+        }
+        None => Err(ManagerError::OutsideServiceWindow(format!(
+            "{} hasn't written in the last 24 hours, only template messages can be sent",
+            receiver
+        ))),
+    }
+}
+
+// Validates template messages against the registered definitions before calling meta
+fn check_template(message: &MessageRequest) -> Result<(), ManagerError> {
+    let template = match &message.content.template {
+        Some(template) if message.message_type == MessageType::Template.as_str() => template,
+        _ => return Ok(()),
+    };
+
+    let definitions = match get_templates(false) {
+        Ok(definitions) => definitions,
+        Err(err) => {
+            // Let meta decide if the definitions can't be obtained
+            error!("Couldnt obtain template definitions: {}", err);
+            return Ok(());
+        }
+    };
+
+    templates::validate_template(template, &definitions)
+}
+
+// Template definitions from cache, or from meta if not cached or refresh is requested
+fn get_templates(refresh: bool) -> Result<Vec<TemplateDefinition>, ManagerError> {
+    if !refresh {
+        match get_cached_templates() {
+            Ok(Some(definitions)) => return Ok(definitions),
+            Ok(None) => {}
+            Err(err) => error!("Couldnt obtain cached templates: {}", err),
+        }
+    }
+
+    info!("Obtaining templates from meta");
+    let definitions = templates::list_templates()?;
+
+    if let Err(err) = cache_templates(&definitions) {
+        error!("Couldnt cache templates: {}", err);
+    }
+
+    Ok(definitions)
+}
+
+pub fn list_templates(refresh: bool) -> Result<Vec<TemplateDefinition>, StandardResponse> {
+    get_templates(refresh).map_err(|err| {
+        error!("{}", err);
+        StandardResponse::error(err)
+    })
+}
+
+pub fn create_template(template: TemplateDefinition) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+
+    info!("Creating template {}", template.name);
+    match templates::create_template(&template) {
+        Ok(created) => {
+            response.references.push(ModifiedReference {
+                system: "WHATSAPP".to_string(),
+                reference: created.id,
+            });
+        }
+        Err(err) => {
+            error!("Couldnt create template: {}", err);
+            response.errors = Some(vec![err.into()]);
+            return Err(response);
+        }
+    }
+
+    // New template has to be visible for validation right away
+    if let Err(err) = clear_cached_templates() {
+        error!("Couldnt clear cached templates: {}", err);
+    }
+
+    Ok(response)
+}
+
+pub fn delete_template(name: &str) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+
+    info!("Deleting template {}", name);
+    if let Err(err) = templates::delete_template(name) {
+        error!("Couldnt delete template: {}", err);
+        response.errors = Some(vec![err.into()]);
+        return Err(response);
+    }
+
+    response.references.push(ModifiedReference {
+        system: "WHATSAPP".to_string(),
+        reference: name.to_string(),
+    });
+
+    if let Err(err) = clear_cached_templates() {
+        error!("Couldnt clear cached templates: {}", err);
+    }
+
+    Ok(response)
+}
+
+// The message must be marked as read on the business number that received it
+pub fn mark_message_read(
+    message_id: &str,
+    sender: Option<&str>,
+    typing: bool,
+) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+
+    let config = config::get();
+    let sender = match sender {
+        Some(sender) => match config.sender(Some(sender), 0) {
+            Ok(sender) => sender,
+            Err(err) => {
+                error!("{}", err);
+                response.errors = Some(vec![ManagerError::Validation(err).into()]);
+                return Err(response);
+            }
+        },
+        None => config.phone_number_id.as_str(),
+    };
+
+    info!("Marking message {} as read", message_id);
+    if let Err(err) = mark_as_read(sender, message_id, typing) {
+        error!("Couldnt mark message as read: {}", err);
+        response.errors = Some(vec![err.into()]);
+        return Err(response);
+    }
+
+    response.references.push(ModifiedReference {
+        system: "WHATSAPP".to_string(),
+        reference: message_id.to_string(),
+    });
+
+    Ok(response)
+}
+
+pub fn upload_media(
+    data: &[u8],
+    mime_type: &str,
+    filename: Option<String>,
+) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut references = vec![];
+
+    if let Err(err) = media::validate_upload(data, mime_type) {
+        error!("{}", err);
+        response.errors = Some(vec![err.into()]);
+        return Err(response);
+    }
+
+    let hash = media::media_hash(data);
+
+    // Reuse the media id if the same file was already uploaded
+    info!("Looking for uploaded media with hash {}", hash);
+    match get_media_id(&hash) {
+        Ok(Some(media_id)) => {
+            info!("Media already uploaded with id: {}", media_id);
+            references.push(ModifiedReference {
+                system: "WHATSAPP".to_string(),
+                reference: media_id,
+            });
+            references.push(ModifiedReference {
+                system: "REDIS".to_string(),
+                reference: format!("media-ids:{}", hash),
+            });
+
+            response.references = references;
+            return Ok(response);
+        }
+        Ok(None) => {}
+        Err(err) => error!("Couldnt obtain cached media id: {}", err),
+    }
+
+    info!("Uploading media");
+    let filename = filename.unwrap_or(hash.clone());
+    let uploaded = match media::upload_media(data, mime_type, &filename) {
+        Ok(uploaded) => uploaded,
+        Err(err) => {
+            error!("Couldnt upload media: {}", err);
+            response.errors = Some(vec![err.into()]);
+            return Err(response);
+        }
+    };
+
+    references.push(ModifiedReference {
+        system: "WHATSAPP".to_string(),
+        reference: uploaded.id.clone(),
+    });
+
+    // A failed cache write only means the next upload of this file is not deduplicated
+    match set_media_id(&hash, &uploaded.id) {
+        Ok(key) => references.push(ModifiedReference {
+            system: "REDIS".to_string(),
+            reference: key,
+        }),
+        Err(err) => error!("Couldnt cache media id: {}", err),
+    }
+
+    response.references = references;
+    Ok(response)
+}
+
 pub fn webhook_message(event: Event) -> Result<StandardResponse, StandardResponse> {
     trace!("{}", serde_json::to_string_pretty(&event).unwrap());
 
     let mut response: StandardResponse = StandardResponse::new();
-    let mut errors: Vec<String> = vec![];
+    let mut errors: Vec<ResponseError> = vec![];
     let mut references = vec![];
 
-    if event.entry[0].changes[0].value.messages.is_none() {
-        error!("Not a user message");
-        errors.push("Not a user message".to_string());
+    // Meta can batch several entries, changes and messages on a single request
+    let message_events = event.split_messages();
+    let statuses = event.statuses();
 
+    // Other fields, like template status or account updates, are acknowledged without processing
+    if message_events.is_empty() && statuses.is_empty() {
+        info!("Event has no messages or statuses, ignoring it");
+        return Ok(response);
+    }
+
+    // Each message and status is processed independently, a failure doesn't stop the rest of the batch
+    info!("Processing {} messages", message_events.len());
+    for message_event in message_events {
+        let message_id = match message_event.message() {
+            Some(message) => message.id.clone(),
+            None => continue,
+        };
+
+        // Meta and the webhook worker retry deliveries, a message is only processed once
+        if !claim_work(PROCESSED_MESSAGES, &message_id, &mut errors) {
+            continue;
+        }
+
+        let result = process_message(message_event);
+        finish_work(PROCESSED_MESSAGES, &message_id, result.is_ok());
+
+        collect_response(&message_id, result, &mut references, &mut errors);
+    }
+
+    info!("Processing {} statuses", statuses.len());
+    for (business_number, status) in statuses {
+        let message_id = status.id.clone();
+
+        // The same status is sent again on retries, its history entry and receipt are only added once
+        let status_key = format!("{}:{}:{}", status.id, status.status, status.timestamp);
+        if !claim_work(PROCESSED_STATUSES, &status_key, &mut errors) {
+            continue;
+        }
+
+        let result = process_status(&business_number, status);
+        finish_work(PROCESSED_STATUSES, &status_key, result.is_ok());
+
+        collect_response(&message_id, result, &mut references, &mut errors);
+    }
+
+    response.references = references;
+
+    if !errors.is_empty() {
         response.errors = Some(errors);
-        return  Err(response)
+        Err(response)
+    } else {
+        Ok(response)
     }
+}
 
-    let phone_number = &event.entry[0].changes[0].value.messages.as_ref().unwrap()[0]
-        .from
-        .clone();
-    let message_id = &event.entry[0].changes[0].value.messages.as_ref().unwrap()[0]
-        .id
-        .clone();
+// False when the work is already done or another consumer is doing it, in which case an error
+// is added so the event is retried and skipped once that consumer is done
+fn claim_work(namespace: &str, id: &str, errors: &mut Vec<ResponseError>) -> bool {
+    match claim(namespace, id) {
+        Ok(Claim::Claimed) => true,
+        Ok(Claim::Processed) => {
+            info!("{} already processed, skipping", id);
+            false
+        }
+        Ok(Claim::Processing) => {
+            info!("{} is being processed by another consumer", id);
+            errors.push(
+                ManagerError::Internal(format!("{} is being processed by another consumer", id))
+                    .into(),
+            );
+            false
+        }
+        Err(err) => {
+            error!("Couldnt claim {}: {}", id, err);
+            errors.push(err.into());
+            false
+        }
+    }
+}
+
+// Failed work is released, the webhook worker retries the whole event
+fn finish_work(namespace: &str, id: &str, succeeded: bool) {
+    let res = if succeeded {
+        mark_processed(namespace, id)
+    } else {
+        release_claim(namespace, id)
+    };
+
+    if let Err(err) = res {
+        error!("Couldnt finish the claim of {}: {}", id, err);
+    }
+}
+
+fn collect_response(
+    message_id: &str,
+    result: Result<StandardResponse, StandardResponse>,
+    references: &mut Vec<ModifiedReference>,
+    errors: &mut Vec<ResponseError>,
+) {
+    let message_response = match result {
+        Ok(message_response) => message_response,
+        Err(message_response) => {
+            error!("Message {} processed with errors", message_id);
+            message_response
+        }
+    };
+
+    references.extend(message_response.references);
+    for mut err in message_response.errors.unwrap_or_default() {
+        err.message = format!("{}: {}", message_id, err.message);
+        errors.push(err);
+    }
+}
+
+fn process_status(
+    business_number: &str,
+    status: Status,
+) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<ResponseError> = vec![];
+    let mut references = vec![];
+
+    info!("Message {} status: {}", status.id, status.status);
+
+    // Store status history
+    match add_status_history(&status) {
+        Ok(key) => references.push(ModifiedReference {
+            system: "REDIS".to_string(),
+            reference: key,
+        }),
+        Err(err) => {
+            errors.push(err.into());
+            response.errors = Some(errors);
+            return Err(response);
+        }
+    }
+
+    // Only move the outgoing message status forward
+    let current = get_status_history(&status.id)
+        .unwrap_or_default()
+        .into_iter()
+        .max_by_key(|status| status.rank())
+        .unwrap_or(status.clone());
+
+    let register_id = match set_outgoing_status(business_number, &current) {
+        Ok(Some(key)) => {
+            references.push(ModifiedReference {
+                system: "REDIS".to_string(),
+                reference: key.clone(),
+            });
+            Some(key)
+        }
+        Ok(None) => {
+            info!("Message {} was not sent by whatsapp manager", current.id);
+            None
+        }
+        Err(err) => {
+            errors.push(err.into());
+            None
+        }
+    };
+
+    // Notify the system that sent the message, unless the status arrived after a newer one
+    if let Some(register_id) = register_id.filter(|_| status.rank() >= current.rank()) {
+        match get_outgoing_system(&register_id) {
+            Ok(Some(system_id)) => match unix_now() {
+                Ok(now) => {
+                    let notification = StatusNotification {
+                        timestamp: now.as_millis().to_string(),
+                        destination_system: system_id.to_string(),
+                        phone_number: status.recipient_id.clone(),
+                        message_id: status.id.clone(),
+                        status: status.status.clone(),
+                        errors: status.errors.clone(),
+                        register_id,
+                    };
+
+                    info!("Notifying status to system {}", system_id);
+                    match publish_status(&notification) {
+                        Ok(channel) => references.push(ModifiedReference {
+                            system: "REDIS".to_string(),
+                            reference: channel,
+                        }),
+                        Err(err) => errors.push(err.into()),
+                    }
+                }
+                Err(err) => errors.push(err.into()),
+            },
+            Ok(None) => error!("Outgoing message {} has no system id", register_id),
+            Err(err) => errors.push(err.into()),
+        }
+    }
+
+    response.references = references;
+
+    if !errors.is_empty() {
+        response.errors = Some(errors);
+        Err(response)
+    } else {
+        Ok(response)
+    }
+}
+
+pub fn get_message_status(message_id: &str) -> Result<Option<MessageStatus>, StandardResponse> {
+    let history = match get_status_history(message_id) {
+        Ok(history) => history,
+        Err(err) => {
+            error!("{}", err);
+            let mut response = StandardResponse::new();
+            response.errors = Some(vec![err.into()]);
+            return Err(response);
+        }
+    };
+
+    let current = match history.iter().max_by_key(|status| status.rank()) {
+        Some(current) => current.clone(),
+        None => return Ok(None),
+    };
+
+    Ok(Some(MessageStatus {
+        id: message_id.to_string(),
+        status: current.status,
+        recipient_id: current.recipient_id,
+        history,
+    }))
+}
+
+fn process_message(event: Event) -> Result<StandardResponse, StandardResponse> {
+    let mut response: StandardResponse = StandardResponse::new();
+    let mut errors: Vec<ResponseError> = vec![];
+    let mut references = vec![];
+
+    let message = match event.message() {
+        Some(message) => message,
+        None => {
+            return Err(StandardResponse::error(ManagerError::Validation(
+                "Webhook event has no message".to_string(),
+            )))
+        }
+    };
+    let phone_number = &message.from;
+    let message_id = &message.id;
+
+    // Messages to numbers not registered as senders couldn't be answered
+    let business_number = event.business_phone_number_id().to_string();
+    if !config::get().is_sender(&business_number) {
+        error!("Business number {} is not registered", business_number);
+        response.errors = Some(vec![ManagerError::Validation(format!(
+            "Business number {} is not registered",
+            business_number
+        ))
+        .into()]);
+        return Err(response);
+    }
 
     info!("Getting user last message reference");
-    let message_reference = get_user_last_message(&phone_number).unwrap();
+    let message_reference = match get_user_last_message(&business_number, phone_number) {
+        Ok(message_reference) => message_reference,
+        Err(err) => return Err(StandardResponse::error(err)),
+    };
 
     let mut expired_message = false;
     if message_reference != "" {
         // Get user last message linked to previously obtained reference
         info!("Getting user last message");
-        let message = get_user_message(message_reference, &phone_number).unwrap();
+        let last_message = match get_user_message(&business_number, message_reference, phone_number)
+        {
+            Ok(last_message) => last_message,
+            Err(err) => return Err(StandardResponse::error(err)),
+        };
 
         // Check expiration time for user last message
-        let time_as_integer = &message.entry[0].changes[0].value.messages.as_ref().unwrap()[0]
-            .timestamp
-            .parse::<i64>()
-            .unwrap();
-
-        let parsed_time = OffsetDateTime::from_unix_timestamp(*time_as_integer)
-            .unwrap()
-            .to_offset(offset!(-3));
+        let last_timestamp = match last_message
+            .message()
+            .and_then(|last_message| last_message.timestamp.parse::<i64>().ok())
+        {
+            Some(last_timestamp) => last_timestamp,
+            None => {
+                return Err(StandardResponse::error(ManagerError::Internal(format!(
+                    "Last message of {} has no valid timestamp",
+                    phone_number
+                ))))
+            }
+        };
 
-        let time_difference = SystemTime::now().duration_since(SystemTime::from(parsed_time));
+        let now = match unix_now() {
+            Ok(now) => now.as_secs() as i64,
+            Err(err) => return Err(StandardResponse::error(err)),
+        };
+        let time_difference = now - last_timestamp;
 
-        info!(
-            "since last message: {} secs",
-            time_difference.as_ref().unwrap().as_secs()
-        );
+        info!("since last message: {} secs", time_difference);
 
         // If message was 6 hours or more ago
-        if time_difference.unwrap().as_secs() > 21600 {
+        if time_difference > 21600 {
             info!("Message expired");
             // reset user mode to 0
-            let res = set_user_mode(phone_number, "100");
+            let res = set_user_mode(&business_number, phone_number, "100");
 
-            if res.is_err(){
+            if res.is_err() {
                 error!("{}", res.as_ref().unwrap_err())
             }
             expired_message = true;
@@ -156,74 +755,146 @@ pub fn webhook_message(event: Event) -> Result<StandardResponse, StandardRespons
     }
 
     info!("Getting user mode");
-    let mode = get_user_mode(phone_number).unwrap();
+    let mode = match get_user_mode(&business_number, phone_number) {
+        Ok(mode) => mode,
+        Err(err) => return Err(StandardResponse::error(err)),
+    };
 
     // Get mode destination systems
     info!("Gettings destionation systems");
-    let destination_system = get_destination_system(mode);
+    let destination_system = match get_destination_system(mode) {
+        Ok(destination_system) => destination_system,
+        Err(err) => return Err(StandardResponse::error(err)),
+    };
 
     // Store json message on redis
     info!("Storing message");
     let json_result = store_message(
         &event.clone(),
+        &business_number,
         phone_number,
         message_id,
         "incoming-messages",
     );
 
     match json_result {
-        Ok(_) => references.push(ModifiedReference {
+        Ok(key) => references.push(ModifiedReference {
             system: "REDIS".to_string(),
-            reference: format!("incoming-messages:{}:{}", phone_number, message_id),
+            reference: key,
         }),
-        Err(err) => errors.push(format!("{}", err)),
+        Err(err) => errors.push(err.into()),
+    }
+
+    // Download customer media so downstream systems get the file and not only the meta id
+    let mut media_reference = None;
+    if let Some(media) = message.media() {
+        info!("Persisting message media");
+        let persisted = media::persist_media(
+            &media.id,
+            &media.sha256,
+            &media::media_key(phone_number, message_id),
+            blob_store::default_store().as_ref(),
+        );
+
+        match persisted {
+            Ok(reference) => {
+                references.push(ModifiedReference {
+                    system: "STORAGE".to_string(),
+                    reference: reference.clone(),
+                });
+                media_reference = Some(reference);
+            }
+            Err(err) => {
+                // Published without the file, the retry would download it but never publish it
+                error!("Couldnt persist media {}: {}", media.id, err);
+                errors.push(err.into());
+
+                response.references = references;
+                response.errors = Some(errors);
+                return Err(response);
+            }
+        }
     }
 
     // Build notification log
-    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+    let timestamp = match unix_now() {
         Ok(n) => n.as_millis().to_string(),
-        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+        Err(err) => return Err(StandardResponse::error(err)),
     };
 
     let log = MessageLog {
         timestamp: timestamp,
-        destination_systems: destination_system.unwrap(),
+        destination_systems: destination_system,
         phone_number: phone_number.to_string(),
         origin_system: "4".to_string(), // META API
         origin: "INCOMING".to_string(), //OUTGOING or INCOMING
         register_id: message_id.to_string(),
+        media_reference,
+        message_type: Some(message.message_type.clone()),
+        location: message.location.clone(),
+        vcards: message
+            .contacts
+            .as_ref()
+            .map(|contacts| contacts.iter().map(vcard::to_vcard).collect()),
+        // Un-reactions are published with no emoji
+        reaction: message.reaction.clone().map(|mut reaction| {
+            if reaction.is_removal() {
+                reaction.emoji = None;
+            }
+            reaction
+        }),
+        context: message.context.clone(),
+        business_phone_number_id: Some(business_number.clone()),
     };
 
-    // Publish notification to channel
-    info!("Publishing message");
-    let publish_res = publish_message(&log, phone_number);
+    // A retry after a later step failed must not notify downstream systems twice
+    let published = match is_processed(PUBLISHED_MESSAGES, message_id) {
+        Ok(published) => published,
+        Err(err) => {
+            error!(
+                "Couldnt check if message {} was published: {}",
+                message_id, err
+            );
+            false
+        }
+    };
 
-    match publish_res {
-        Ok(_) => {
-            references.push(ModifiedReference {
-                system: "REDIS".to_string(),
-                reference: format!("whatsapp-notification:{}", phone_number),
-            });
+    if published {
+        info!("Message {} already published", message_id);
+    } else {
+        // Publish notification to channel
+        info!("Publishing message");
+        if let Err(err) = publish_message(&log, phone_number) {
+            errors.push(err.into());
+
+            response.references = references;
+            response.errors = Some(errors);
+            return Err(response);
+        }
 
-            // Store notification sent to channel
-            println!("Log message");
-            let id = log_message(&log);
+        references.push(ModifiedReference {
+            system: "REDIS".to_string(),
+            reference: format!("whatsapp-notification:{}", phone_number),
+        });
 
-            match id {
-                Ok(redis_id) => {
-                    references.push(ModifiedReference {
-                        system: "REDIS".to_string(),
-                        reference: redis_id,
-                    });
+        if let Err(err) = mark_processed(PUBLISHED_MESSAGES, message_id) {
+            error!("Couldnt mark message {} as published: {}", message_id, err);
+        }
 
-                    // Set message id as last message
-                    let res = set_last_message(&message_id, &phone_number).unwrap();
-                }
-                Err(err) => errors.push(format!("{}", err)),
-            }
+        // Store notification sent to channel, the log is not retried to keep the notification unique
+        match log_message(&log) {
+            Ok(redis_id) => references.push(ModifiedReference {
+                system: "REDIS".to_string(),
+                reference: redis_id,
+            }),
+            Err(err) => error!("Couldnt log message {}: {}", message_id, err),
         }
-        Err(err) => errors.push(format!("{}", err)),
-    };
+    }
+
+    // Set message id as last message
+    if let Err(err) = set_last_message(&business_number, message_id, phone_number) {
+        errors.push(err.into());
+    }
 
     // Build response
     response.references = references;
@@ -238,15 +909,20 @@ pub fn webhook_message(event: Event) -> Result<StandardResponse, StandardRespons
 
 pub fn send_menu(log: MessageLog) -> Result<StandardResponse, StandardResponse> {
     let mut response: StandardResponse = StandardResponse::new();
-    let mut errors: Vec<String> = vec![];
-    let mut references = vec![];
-    let timestamp2 = match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(n) => n.as_millis().to_string(),
-        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
-    };
+    let mut errors: Vec<ResponseError> = vec![];
+    let references = vec![];
+
+    // Replies are sent from the business number the customer wrote to
+    let business_number = log
+        .business_phone_number_id
+        .clone()
+        .unwrap_or(config::get().phone_number_id.clone());
 
     // If user has no mode set(mode 0)
-    let mode = get_user_mode(&log.phone_number).unwrap();
+    let mode = match get_user_mode(&business_number, &log.phone_number) {
+        Ok(mode) => mode,
+        Err(err) => return Err(StandardResponse::error(err)),
+    };
 
     info!("current mode: {}", &mode);
 
@@ -261,198 +937,151 @@ pub fn send_menu(log: MessageLog) -> Result<StandardResponse, StandardResponse>
                 body: Some(
                     "Opciones disponibles:\n 1. Busqueda respuesto.\n 2. Ayuda.".to_string(),
                 ),
-                list: None,
-                buttons: None,
+                ..Default::default()
             },
+            reply_to: None,
+            sender: Some(business_number.clone()),
         };
 
-        set_user_mode(&log.phone_number, "0");
-        send_message(request);
+        if let Err(err) = set_user_mode(&business_number, &log.phone_number, "0") {
+            errors.push(err.into());
+        }
+        send_reply(request, &mut errors);
 
-        return Ok(response);
+        return menu_response(response, references, errors);
     }
 
     // Get user last message id
-    let ws_message_id = get_user_last_message(&log.phone_number);
-
-    if ws_message_id.is_err() {
-        errors.push(ws_message_id.unwrap_err().to_string());
-        response.references = references;
-        response.errors = None;
-
-        return Err(response);
-    }
+    let ws_message_id = match get_user_last_message(&business_number, &log.phone_number) {
+        Ok(ws_message_id) => ws_message_id,
+        Err(err) => return Err(StandardResponse::error(err)),
+    };
 
-    info!("User last message id: {}", ws_message_id.as_ref().unwrap());
+    info!("User last message id: {}", ws_message_id);
 
     // Get user last message content
-    let ws_message: Result<Event, RedisError> = get_user_message(
-        ws_message_id.as_ref().unwrap().to_string(),
-        &log.phone_number,
-    );
-
-    if ws_message.is_err() {
-        errors.push("Error obtaining user message".to_string());
-        response.references = references;
-        response.errors = Some(errors);
+    let ws_message =
+        match get_user_message(&business_number, ws_message_id.clone(), &log.phone_number) {
+            Ok(ws_message) => ws_message,
+            Err(err) => return Err(StandardResponse::error(err)),
+        };
 
-        return Err(response);
-    }
+    let message = match ws_message.message() {
+        Some(message) => message,
+        None => {
+            return Err(StandardResponse::error(ManagerError::Internal(format!(
+                "Stored event {} has no message",
+                ws_message_id
+            ))))
+        }
+    };
 
     // Check if message type is a text message
-    let message_type = String::from(
-        &ws_message.as_ref().unwrap().entry[0].changes[0]
-            .value
-            .messages
-            .as_ref()
-            .unwrap()[0]
-            .message_type,
-    );
+    let message_type = message.message_type.clone();
 
-    info!("message Type: {}", &message_type);
+    info!("message Type: {}", message_type.as_str());
 
     // Returns error is user send a non plain text message
-    if message_type != "text" {
-        errors.push("Message type has to be a text message, with only the number of the mode to be selected.".to_string());
-
-        let request = MessageRequest{
-            system_id: 1,
-            to: vec![log.phone_number],
-            message_type: "text".to_string(),
-            content: MessageContent {
-                body: Some("1. La opcion ingresada no es valida, debe ingresar solamente el numero de la opcion a seleccionar, intente nuevamente.".to_string()),
-                list: None,
-                buttons: None,
-            },
-        };
-
-        send_message(request);
-        response.references = references;
-        response.errors = Some(errors);
-
-        return Err(response);
-    }
-
-
-    // MODE MANAGEMENT
-
-    // Check if user wanna change mode
-    info!("mode: {}", mode);
-    info!("content: {}", ws_message.as_ref().unwrap().entry[0].changes[0]
-        .value
-        .messages
-        .as_ref()
-        .unwrap()[0]
-        .text
-        .as_ref()
-        .unwrap()
-        .body
-        .to_lowercase());
-
-    println!("mode: {}", mode);
-    println!("content: {}", ws_message.as_ref().unwrap().entry[0].changes[0]
-        .value
-        .messages
-        .as_ref()
-        .unwrap()[0]
+    let text = match message
         .text
         .as_ref()
-        .unwrap()
-        .body
-        .to_lowercase());
-
-    if mode != 0
-        && ws_message.as_ref().unwrap().entry[0].changes[0]
-        .value
-        .messages
-        .as_ref()
-        .unwrap()[0]
-        .text
-        .as_ref()
-        .unwrap()
-        .body
-        .to_lowercase()
-        == "salir"
+        .filter(|_| message_type == MessageKind::Text)
     {
-        info!("User exiting mode {}", &mode);
-
-        set_user_mode(&log.phone_number, "100");
-        send_menu(log.clone());
-
-        response.references = references;
-        response.errors = None;
-        return Ok(response);
-    }
+        Some(text) => text.body.to_lowercase(),
+        None => {
+            errors.push(ManagerError::Validation("Message type has to be a text message, with only the number of the mode to be selected.".to_string()).into());
+
+            let request = MessageRequest{
+                system_id: 1,
+                to: vec![log.phone_number],
+                message_type: "text".to_string(),
+                content: MessageContent {
+                    body: Some("1. La opcion ingresada no es valida, debe ingresar solamente el numero de la opcion a seleccionar, intente nuevamente.".to_string()),
+                    ..Default::default()
+                },
+                reply_to: None,
+                sender: Some(business_number.clone()),
+            };
+
+            send_reply(request, &mut errors);
+            response.references = references;
+            response.errors = Some(errors);
+
+            return Err(response);
+        }
+    };
 
-    // check if message is a number
-    let option = ws_message.as_ref().unwrap().entry[0].changes[0]
-        .value
-        .messages
-        .as_ref()
-        .unwrap()[0]
-        .text
-        .as_ref()
-        .unwrap()
-        .body
-        .parse::<u8>();
+    // MODE MANAGEMENT
 
-    // Send error is message cant be parsed to a number
-    if option.is_err() {
-        errors.push("La opcion ingresada no es valida, debe ingresar solamente el numero de la opcion a seleccionar, intente nuevamente.".to_string());
+    // Check if user wanna change mode
+    info!("mode: {}", mode);
+    info!("content: {}", text);
 
-        let request = MessageRequest{
-            system_id: 1,
-            to: vec![log.phone_number],
-            message_type: "text".to_string(),
-            content: MessageContent {
-                body: Some("La opcion ingresada no es valida, debe ingresar solamente el numero de la opcion a seleccionar, intente nuevamente.".to_string()),
-                list: None,
-                buttons: None,
-            },
-        };
+    if mode != 0 && text == "salir" {
+        info!("User exiting mode {}", &mode);
 
-        send_message(request);
-        response.references = references;
-        response.errors = Some(errors);
+        if let Err(err) = set_user_mode(&business_number, &log.phone_number, "100") {
+            errors.push(err.into());
+        }
+        if let Err(menu) = send_menu(log.clone()) {
+            errors.extend(menu.errors.unwrap_or_default());
+        }
 
-        return Err(response);
+        return menu_response(response, references, errors);
     }
 
-    // Unwraps parsed number
-    let option_number = option.unwrap();
+    // check if message is a number, send error if it cant be parsed
+    let option_number = match text.parse::<u8>() {
+        Ok(option_number) => option_number,
+        Err(_) => {
+            errors.push(ManagerError::Validation("La opcion ingresada no es valida, debe ingresar solamente el numero de la opcion a seleccionar, intente nuevamente.".to_string()).into());
+
+            let request = MessageRequest{
+                system_id: 1,
+                to: vec![log.phone_number],
+                message_type: "text".to_string(),
+                content: MessageContent {
+                    body: Some("La opcion ingresada no es valida, debe ingresar solamente el numero de la opcion a seleccionar, intente nuevamente.".to_string()),
+                    ..Default::default()
+                },
+                reply_to: None,
+                sender: Some(business_number.clone()),
+            };
+
+            send_reply(request, &mut errors);
+            response.references = references;
+            response.errors = Some(errors);
+
+            return Err(response);
+        }
+    };
 
     info!("Option selected: {}", &option_number);
 
     // Get destination systems for the selected number
-    let systems = get_destination_system(option_number as u16);
-
-    // Error obtaining mode destination systems
-    if systems.is_err() {
-        errors.push(systems.as_ref().unwrap_err().to_string());
-
-        response.references = references;
-        response.errors = Some(errors);
-
-        return Err(response);
-    }
+    let systems = match get_destination_system(option_number as u16) {
+        Ok(systems) => systems,
+        Err(err) => return Err(StandardResponse::error(err)),
+    };
 
-    info!("mode {} systems: {:?}",option_number,systems.as_ref().unwrap());
+    info!("mode {} systems: {:?}", option_number, systems);
 
     // error is destination systems for mode is an empty list
-    if systems.as_ref().unwrap().is_empty() {
-        errors.push("El modo seleccionado no se encuentra entre las opciones disponibles, selecciona un modo listado.".to_string());
+    if systems.is_empty() {
+        errors.push(ManagerError::Validation("El modo seleccionado no se encuentra entre las opciones disponibles, selecciona un modo listado.".to_string()).into());
         let request = MessageRequest{
             system_id: 1,
             to: vec![log.phone_number.clone()],
             message_type: "text".to_string(),
             content: MessageContent {
                 body: Some("El modo seleccionado no se encuentra entre las opciones disponibles, selecciona un modo listado.".to_string()),
-                list: None,
-                buttons: None,
+                ..Default::default()
             },
+            reply_to: None,
+            sender: Some(business_number.clone()),
         };
 
-        send_message(request);
+        send_reply(request, &mut errors);
 
         response.references = references;
         response.errors = Some(errors);
@@ -460,32 +1089,45 @@ pub fn send_menu(log: MessageLog) -> Result<StandardResponse, StandardResponse>
         return Err(response);
     }
 
-
     // If user is in mode selection
-    if mode == 0{
-
+    if mode == 0 {
         info!("Processing user option selection");
 
         // Set user new mode
-        set_user_mode(&log.phone_number, &option_number.to_string());
+        if let Err(err) = set_user_mode(
+            &business_number,
+            &log.phone_number,
+            &option_number.to_string(),
+        ) {
+            errors.push(err.into());
+        }
 
         // Notify user selection
-        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        let timestamp = match unix_now() {
             Ok(n) => n.as_millis().to_string(),
-            Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+            Err(err) => return Err(StandardResponse::error(err)),
         };
 
         // Notify selection successful
         let notification_log = MessageLog {
             timestamp: timestamp,
-            destination_systems: systems.as_ref().unwrap().clone(),
+            destination_systems: systems.clone(),
             phone_number: String::from(&log.phone_number),
             origin_system: "1".to_string(),
             origin: "OUTGOING".to_string(),
-            register_id: ws_message_id.as_ref().unwrap().clone(),
+            register_id: ws_message_id.clone(),
+            media_reference: None,
+            message_type: None,
+            location: None,
+            vcards: None,
+            reaction: None,
+            context: None,
+            business_phone_number_id: Some(business_number.clone()),
         };
 
-        publish_message(&notification_log, &log.phone_number);
+        if let Err(err) = publish_message(&notification_log, &log.phone_number) {
+            errors.push(err.into());
+        }
 
         let request = MessageRequest{
             system_id: 1,
@@ -493,41 +1135,165 @@ pub fn send_menu(log: MessageLog) -> Result<StandardResponse, StandardResponse>
             message_type: "text".to_string(),
             content: MessageContent {
                 body: Some(format!("Ha seleccionado el opcion {}, si desea seleccionar otra opcion esriba 'salir' en el chat.", option_number)),
-                list: None,
-                buttons: None,
+                ..Default::default()
             },
+            reply_to: None,
+            sender: Some(business_number.clone()),
         };
 
-        send_message(request);
+        send_reply(request, &mut errors);
     }
 
     // If there a system mode selected
-    if mode != 0 && mode !=100 {
-
+    if mode != 0 && mode != 100 {
         info!("Sending message to user selected option system");
 
-        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        let timestamp = match unix_now() {
             Ok(n) => n.as_millis().to_string(),
-            Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+            Err(err) => return Err(StandardResponse::error(err)),
         };
 
         // Notify selection successful
         let notification_log = MessageLog {
             timestamp: timestamp,
-            destination_systems: systems.as_ref().unwrap().clone(),
+            destination_systems: systems.clone(),
             phone_number: String::from(&log.clone().phone_number),
             origin_system: "1".to_string(),
             origin: "INCOMING".to_string(),
-            register_id: ws_message_id.as_ref().unwrap().clone(),
+            register_id: ws_message_id.clone(),
+            media_reference: None,
+            message_type: Some(message_type.clone()),
+            location: log.location.clone(),
+            vcards: log.vcards.clone(),
+            reaction: log.reaction.clone(),
+            context: log.context.clone(),
+            business_phone_number_id: Some(business_number.clone()),
         };
 
-        publish_message(&notification_log, &log.phone_number);
+        if let Err(err) = publish_message(&notification_log, &log.phone_number) {
+            errors.push(err.into());
+        }
 
+        // The selected system claims the message, so the customer knows it is being answered
+        if let Err(err) = mark_as_read(&business_number, &ws_message_id, true) {
+            error!("{}", err);
+        }
     }
 
+    menu_response(response, references, errors)
+}
+
+// Replies sent by the menu, their failures are added to the menu response
+fn send_reply(request: MessageRequest, errors: &mut Vec<ResponseError>) {
+    if let Err(response) = send_message(request) {
+        errors.extend(response.errors.unwrap_or_default());
+    }
+}
+
+fn menu_response(
+    mut response: StandardResponse,
+    references: Vec<ModifiedReference>,
+    errors: Vec<ResponseError>,
+) -> Result<StandardResponse, StandardResponse> {
     response.references = references;
-    response.errors = None;
 
-    Ok(response)
+    if errors.is_empty() {
+        Ok(response)
+    } else {
+        response.errors = Some(errors);
+        Err(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    const NOW: i64 = 1_700_000_000;
+    const RECEIVER: &str = "5491155550000";
+
+    fn text_message() -> MessageRequest {
+        MessageRequest {
+            system_id: 2,
+            to: vec![RECEIVER.to_string()],
+            message_type: MessageType::Text.as_str().to_string(),
+            content: MessageContent {
+                body: Some("Tu auto está listo".to_string()),
+                ..Default::default()
+            },
+            reply_to: Some("wamid.HBgL".to_string()),
+            sender: Some("norte".to_string()),
+        }
+    }
+
+    fn fallback() -> TemplateMessage {
+        TemplateMessage {
+            name: "retomar_conversacion".to_string(),
+            language: "es_AR".to_string(),
+            components: vec![],
+        }
+    }
+
+    fn no_fallback() -> Result<Option<TemplateMessage>, ManagerError> {
+        Ok(None)
+    }
+
+    #[test]
+    fn sends_message_inside_the_window() {
+        let last_inbound = Some(NOW - SERVICE_WINDOW_SECS + 1);
+
+        let message = apply_service_window(&text_message(), RECEIVER, last_inbound, NOW, || {
+            panic!("The fallback template is only needed outside the window")
+        })
+        .unwrap();
+
+        assert_eq!(message.message_type, "text");
+        assert_eq!(message.content.body.as_deref(), Some("Tu auto está listo"));
+    }
+
+    #[test]
+    fn rejects_message_outside_the_window_without_fallback() {
+        for last_inbound in [Some(NOW - SERVICE_WINDOW_SECS), None] {
+            let err =
+                apply_service_window(&text_message(), RECEIVER, last_inbound, NOW, no_fallback)
+                    .err()
+                    .unwrap();
+
+            assert_eq!(err.code(), "OUTSIDE_SERVICE_WINDOW");
+            assert_eq!(err.status(), 422);
+        }
+    }
+
+    #[test]
+    fn sends_fallback_template_outside_the_window() {
+        let last_inbound = Some(NOW - 2 * SERVICE_WINDOW_SECS);
+
+        let message = apply_service_window(&text_message(), RECEIVER, last_inbound, NOW, || {
+            Ok(Some(fallback()))
+        })
+        .unwrap();
+
+        assert_eq!(message.message_type, "template");
+        assert_eq!(message.to, vec![RECEIVER.to_string()]);
+        assert!(message.content.body.is_none());
+        assert_eq!(
+            message.content.template.unwrap().name,
+            "retomar_conversacion"
+        );
+        assert_eq!(message.reply_to.as_deref(), Some("wamid.HBgL"));
+        assert_eq!(message.sender.as_deref(), Some("norte"));
+    }
+
+    #[test]
+    fn keeps_fallback_lookup_errors() {
+        let err = apply_service_window(&text_message(), RECEIVER, None, NOW, || {
+            Err(ManagerError::Internal(
+                "Couldnt read the fallback template".to_string(),
+            ))
+        })
+        .err()
+        .unwrap();
+
+        assert_eq!(err.code(), "INTERNAL_ERROR");
+    }
 }
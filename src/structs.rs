@@ -5,6 +5,8 @@ pub mod webhooks {
 
     use crate::structs::Storable;
     use serde_derive::{Deserialize, Serialize};
+    use serde_json::Value;
+    use std::collections::HashMap;
 
     #[derive(Serialize, Deserialize, Clone)]
     pub struct Event {
@@ -14,6 +16,68 @@ pub mod webhooks {
 
     impl Storable for Event {}
 
+    impl Event {
+        // Splits a batch into one event per message, keeping its entry and change metadata
+        pub fn split_messages(&self) -> Vec<Event> {
+            let mut events = vec![];
+
+            for entry in &self.entry {
+                for change in &entry.changes {
+                    for message in change.value.messages.iter().flatten() {
+                        events.push(Event {
+                            object: self.object.clone(),
+                            entry: vec![Entry {
+                                id: entry.id.clone(),
+                                changes: vec![Change {
+                                    field: change.field.clone(),
+                                    value: ChangeValue {
+                                        messaging_product: change.value.messaging_product.clone(),
+                                        metadata: change.value.metadata.clone(),
+                                        contacts: change.value.contacts.clone(),
+                                        messages: Some(vec![message.clone()]),
+                                        statuses: None,
+                                    },
+                                }],
+                            }],
+                        });
+                    }
+                }
+            }
+
+            events
+        }
+
+        // Statuses with the phone number id of the business number that sent the message
+        pub fn statuses(&self) -> Vec<(String, Status)> {
+            self.entry
+                .iter()
+                .flat_map(|entry| entry.changes.iter())
+                .flat_map(|change| {
+                    change.value.statuses.iter().flatten().map(|status| {
+                        (
+                            change.value.metadata.phone_number_id.clone(),
+                            status.clone(),
+                        )
+                    })
+                })
+                .collect()
+        }
+
+        // First message of the event, events from split_messages have only one
+        pub fn message(&self) -> Option<&Message> {
+            self.entry
+                .first()
+                .and_then(|entry| entry.changes.first())
+                .and_then(|change| change.value.messages.as_ref())
+                .and_then(|messages| messages.first())
+        }
+
+        // Business number that received the first message of the event
+        pub fn business_phone_number_id(&self) -> &str {
+            &self.entry[0].changes[0].value.metadata.phone_number_id
+        }
+    }
+
     #[derive(Serialize, Deserialize)]
     pub struct MediaData {
         pub url: String,
@@ -38,7 +102,10 @@ pub mod webhooks {
 
     #[derive(Serialize, Deserialize, Clone)]
     pub struct ChangeValue {
+        // Only message changes have them, other fields like template status updates don't
+        #[serde(default)]
         messaging_product: String,
+        #[serde(default)]
         metadata: ChangeMetadata,
         contacts: Option<Vec<Contact>>,
         pub(crate) messages: Option<Vec<Message>>,
@@ -47,11 +114,25 @@ pub mod webhooks {
 
     #[derive(Serialize, Deserialize, Clone)]
     pub struct Status {
-        id: String,
-        status: String,
-        timestamp: String,
-        recipient_id: String,
+        pub(crate) id: String,
+        pub(crate) status: String,
+        pub(crate) timestamp: String,
+        pub(crate) recipient_id: String,
         conversation: Option<Conversation>,
+        pub errors: Option<Vec<MessageError>>,
+    }
+
+    impl Status {
+        // Meta doesn't guarantee statuses arrive in order, so a read can't be overwritten by a late delivered
+        pub fn rank(&self) -> u8 {
+            match self.status.as_str() {
+                "sent" => 1,
+                "delivered" => 2,
+                "read" => 3,
+                "failed" => 4,
+                _ => 0,
+            }
+        }
     }
 
     #[derive(Serialize, Deserialize, Clone)]
@@ -66,10 +147,10 @@ pub mod webhooks {
         origin_type: String,
     }
 
-    #[derive(Serialize, Deserialize, Clone)]
+    #[derive(Serialize, Deserialize, Clone, Default)]
     pub struct ChangeMetadata {
         display_phone_number: String,
-        phone_number_id: String,
+        pub(crate) phone_number_id: String,
     }
 
     #[derive(Serialize, Deserialize, Clone)]
@@ -80,25 +161,277 @@ pub mod webhooks {
 
     #[derive(Serialize, Deserialize, Clone)]
     pub struct Message {
-        pub(crate) context: Option<Context>,
+        pub context: Option<Context>,
         pub(crate) from: String,
         pub(crate) id: String,
         pub(crate) timestamp: String,
 
         #[serde(alias = "type")]
-        pub(crate) message_type: String,
-        pub image: Option<Image>,
+        pub(crate) message_type: MessageKind,
         pub(crate) text: Option<Text>,
+        pub image: Option<Media>,
+        pub audio: Option<Media>,
+        pub video: Option<Media>,
+        pub document: Option<Media>,
+        pub sticker: Option<Media>,
+        pub location: Option<Location>,
+        pub contacts: Option<Vec<SharedContact>>,
+        pub reaction: Option<Reaction>,
+        pub order: Option<Order>,
+        pub system: Option<SystemMessage>,
         pub(crate) button: Option<Button>,
         pub(crate) interactive: Option<Interactive>,
+        pub errors: Option<Vec<MessageError>>,
+
+        // Anything meta adds that is not modeled yet, kept so it is stored and not lost
+        #[serde(flatten)]
+        pub extra: HashMap<String, Value>,
     }
 
+    impl Message {
+        pub fn media(&self) -> Option<&Media> {
+            self.image
+                .as_ref()
+                .or(self.audio.as_ref())
+                .or(self.video.as_ref())
+                .or(self.document.as_ref())
+                .or(self.sticker.as_ref())
+        }
+    }
+
+    // Serialized as the plain type string, so unknown types reach downstream systems unchanged
+    #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+    #[serde(from = "String", into = "String")]
+    pub enum MessageKind {
+        Text,
+        Image,
+        Audio,
+        Video,
+        Document,
+        Sticker,
+        Location,
+        Contacts,
+        Reaction,
+        Order,
+        System,
+        Button,
+        Interactive,
+        Unsupported,
+        Unknown,
+        // Types meta may add in the future, with the type meta sent
+        Other(String),
+    }
+
+    impl MessageKind {
+        pub fn as_str(&self) -> &str {
+            match self {
+                MessageKind::Text => "text",
+                MessageKind::Image => "image",
+                MessageKind::Audio => "audio",
+                MessageKind::Video => "video",
+                MessageKind::Document => "document",
+                MessageKind::Sticker => "sticker",
+                MessageKind::Location => "location",
+                MessageKind::Contacts => "contacts",
+                MessageKind::Reaction => "reaction",
+                MessageKind::Order => "order",
+                MessageKind::System => "system",
+                MessageKind::Button => "button",
+                MessageKind::Interactive => "interactive",
+                MessageKind::Unsupported => "unsupported",
+                MessageKind::Unknown => "unknown",
+                MessageKind::Other(kind) => kind,
+            }
+        }
+    }
+
+    impl From<String> for MessageKind {
+        fn from(kind: String) -> Self {
+            match kind.as_str() {
+                "text" => MessageKind::Text,
+                "image" => MessageKind::Image,
+                "audio" => MessageKind::Audio,
+                "video" => MessageKind::Video,
+                "document" => MessageKind::Document,
+                "sticker" => MessageKind::Sticker,
+                "location" => MessageKind::Location,
+                "contacts" => MessageKind::Contacts,
+                "reaction" => MessageKind::Reaction,
+                "order" => MessageKind::Order,
+                "system" => MessageKind::System,
+                "button" => MessageKind::Button,
+                "interactive" => MessageKind::Interactive,
+                "unsupported" => MessageKind::Unsupported,
+                "unknown" => MessageKind::Unknown,
+                _ => MessageKind::Other(kind),
+            }
+        }
+    }
+
+    impl From<MessageKind> for String {
+        fn from(kind: MessageKind) -> Self {
+            kind.as_str().to_string()
+        }
+    }
+
+    // Shared by image, audio, video, document and sticker messages
     #[derive(Serialize, Deserialize, Clone)]
-    pub struct Image {
-        pub caption: String,
+    pub struct Media {
+        pub id: String,
         pub mime_type: String,
         pub sha256: String,
-        pub id: String,
+        pub caption: Option<String>,
+        pub filename: Option<String>,
+        pub voice: Option<bool>,
+        pub animated: Option<bool>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct Location {
+        pub latitude: f64,
+        pub longitude: f64,
+        pub name: Option<String>,
+        pub address: Option<String>,
+        pub url: Option<String>,
+    }
+
+    // Also used to send contacts, unset fields are left out as meta expects
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct SharedContact {
+        pub name: ContactName,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub phones: Option<Vec<ContactPhone>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub emails: Option<Vec<ContactEmail>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub addresses: Option<Vec<ContactAddress>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub org: Option<ContactOrg>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub urls: Option<Vec<ContactUrl>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub birthday: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct ContactName {
+        pub formatted_name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub first_name: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub last_name: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub middle_name: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub prefix: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub suffix: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct ContactPhone {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub phone: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(rename = "type")]
+        pub phone_type: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub wa_id: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct ContactEmail {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub email: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(rename = "type")]
+        pub email_type: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct ContactAddress {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub street: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub city: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub state: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub zip: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub country: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub country_code: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(rename = "type")]
+        pub address_type: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct ContactOrg {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub company: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub department: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub title: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct ContactUrl {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub url: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(rename = "type")]
+        pub url_type: Option<String>,
+    }
+
+    // An empty or missing emoji means the customer removed the reaction
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct Reaction {
+        pub message_id: String,
+        pub emoji: Option<String>,
+    }
+
+    impl Reaction {
+        pub fn is_removal(&self) -> bool {
+            self.emoji.as_deref().unwrap_or_default().is_empty()
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct Order {
+        pub catalog_id: String,
+        pub text: Option<String>,
+        pub product_items: Vec<ProductItem>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct ProductItem {
+        pub product_retailer_id: String,
+        // Meta documents these as strings but sends numbers
+        pub quantity: Value,
+        pub item_price: Value,
+        pub currency: String,
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct SystemMessage {
+        pub body: String,
+        #[serde(alias = "type")]
+        pub system_type: Option<String>,
+        pub wa_id: Option<String>,
+        pub new_wa_id: Option<String>,
+        pub identity: Option<String>,
+        pub customer: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    pub struct MessageError {
+        pub code: i64,
+        pub title: String,
+        pub message: Option<String>,
+        pub error_data: Option<Value>,
     }
 
     #[derive(Serialize, Deserialize, Clone)]
@@ -113,6 +446,7 @@ pub mod webhooks {
     pub struct ListReply {
         pub(crate) id: String,
         title: String,
+        description: Option<String>,
     }
 
     #[derive(Serialize, Deserialize, Clone)]
@@ -121,10 +455,13 @@ pub mod webhooks {
         pub(crate) text: String,
     }
 
+    // Set when the customer replies to a message or forwards one, forwarded messages have no id
     #[derive(Serialize, Deserialize, Clone)]
     pub struct Context {
-        from: String,
-        id: String,
+        pub from: Option<String>,
+        pub id: Option<String>,
+        pub forwarded: Option<bool>,
+        pub frequently_forwarded: Option<bool>,
     }
 
     #[derive(Serialize, Deserialize, Clone)]
@@ -141,7 +478,7 @@ pub mod webhooks {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct StandardResponse {
     pub references: Vec<ModifiedReference>,
-    pub errors: Option<Vec<String>>,
+    pub errors: Option<Vec<ResponseError>>,
 }
 
 impl StandardResponse {
@@ -151,6 +488,40 @@ impl StandardResponse {
             errors: None,
         }
     }
+
+    pub fn error(error: impl Into<ResponseError>) -> StandardResponse {
+        StandardResponse {
+            references: vec![],
+            errors: Some(vec![error.into()]),
+        }
+    }
+
+    // Http status of a failed response, the one of its first error
+    pub fn status(&self) -> u16 {
+        self.errors
+            .iter()
+            .flatten()
+            .map(|error| error.status)
+            .find(|status| *status != 0)
+            .unwrap_or(500)
+    }
+}
+
+// code is machine readable, meta fields are only set for graph api errors
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ResponseError {
+    pub code: String,
+    pub message: String,
+    #[serde(skip)]
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta_code: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta_subcode: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fbtrace_id: Option<String>,
 }
 
 pub trait Storable {}
@@ -169,6 +540,34 @@ pub struct MessageLog {
     pub origin_system: String,
     pub origin: String,
     pub register_id: String,
+    pub media_reference: Option<String>,
+    pub message_type: Option<webhooks::MessageKind>,
+    pub location: Option<webhooks::Location>,
+    pub vcards: Option<Vec<String>>,
+    pub reaction: Option<webhooks::Reaction>,
+    pub context: Option<webhooks::Context>,
+    // Phone number id of the business number the conversation belongs to
+    pub business_phone_number_id: Option<String>,
 }
 
 impl Storable for MessageLog {}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MessageStatus {
+    pub id: String,
+    pub status: String,
+    pub recipient_id: String,
+    pub history: Vec<webhooks::Status>,
+}
+
+// Sent only to the system that created the outgoing message
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StatusNotification {
+    pub timestamp: String,
+    pub destination_system: String,
+    pub phone_number: String,
+    pub message_id: String,
+    pub status: String,
+    pub errors: Option<Vec<webhooks::MessageError>>,
+    pub register_id: String,
+}
@@ -0,0 +1,367 @@
+use crate::config;
+use crate::error_manager::{unreadable_body, ManagerError};
+use crate::request_builder::{graph_agent, TemplateMessage, TemplateParameter};
+use log::{debug, error};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Message template as defined on the whatsapp business account
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TemplateDefinition {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub name: String,
+    pub language: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    pub category: String,
+    pub components: Vec<TemplateDefinitionComponent>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TemplateDefinitionComponent {
+    #[serde(rename = "type")]
+    pub component_type: String,
+    // TEXT, IMAGE, VIDEO, DOCUMENT or LOCATION, only for headers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buttons: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub example: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct TemplateListResponse {
+    data: Vec<TemplateDefinition>,
+    paging: Option<Paging>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Paging {
+    next: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CreateTemplateResponse {
+    pub id: String,
+    pub status: Option<String>,
+    pub category: Option<String>,
+}
+
+fn templates_url() -> Result<String, ManagerError> {
+    let config = config::get();
+    let business_account_id =
+        config
+            .business_account_id
+            .as_ref()
+            .ok_or(ManagerError::Configuration(
+                "META_BUSINESS_ACCOUNT_ID is not set, templates can't be managed".to_string(),
+            ))?;
+
+    Ok(format!(
+        "{}/{}/message_templates",
+        config.graph_url(),
+        business_account_id
+    ))
+}
+
+fn authorization() -> String {
+    config::get().authorization()
+}
+
+pub fn list_templates() -> Result<Vec<TemplateDefinition>, ManagerError> {
+    let mut templates = vec![];
+    let mut next = Some(format!("{}?limit=100", templates_url()?));
+
+    // Follow pagination until meta doesn't return a next page
+    while let Some(url) = next {
+        let response_body = graph_agent()
+            .get(&url)
+            .set("Authorization", &authorization())
+            .call()?
+            .into_string()
+            .map_err(unreadable_body)?;
+
+        let page: TemplateListResponse = match serde_json::from_str(&response_body) {
+            Ok(page) => page,
+            Err(err) => {
+                error!("Couldnt parse templates response: {}", response_body);
+                return Err(ManagerError::MetaUnavailable(format!(
+                    "Couldnt parse templates response: {}",
+                    err
+                )));
+            }
+        };
+
+        templates.extend(page.data);
+        next = page.paging.and_then(|paging| paging.next);
+    }
+
+    debug!("Obtained {} templates", templates.len());
+    Ok(templates)
+}
+
+pub fn create_template(
+    template: &TemplateDefinition,
+) -> Result<CreateTemplateResponse, ManagerError> {
+    let response_body = graph_agent()
+        .post(&templates_url()?)
+        .set("Authorization", &authorization())
+        .send_json(ureq::json!(template))?
+        .into_string()
+        .map_err(unreadable_body)?;
+
+    match serde_json::from_str(&response_body) {
+        Ok(response) => Ok(response),
+        Err(err) => {
+            error!(
+                "Couldnt parse template creation response: {}",
+                response_body
+            );
+            Err(ManagerError::MetaUnavailable(format!(
+                "Couldnt parse template creation response: {}",
+                err
+            )))
+        }
+    }
+}
+
+// Deletes every language of the template
+pub fn delete_template(name: &str) -> Result<(), ManagerError> {
+    graph_agent()
+        .delete(&templates_url()?)
+        .query("name", name)
+        .set("Authorization", &authorization())
+        .call()?;
+
+    Ok(())
+}
+
+// Number of distinct {{n}} placeholders in a template text
+pub fn placeholder_count(text: &str) -> usize {
+    let mut placeholders: Vec<&str> = text
+        .split("{{")
+        .skip(1)
+        .filter_map(|part| {
+            part.split_once("}}")
+                .map(|(placeholder, _)| placeholder.trim())
+        })
+        .collect();
+
+    placeholders.sort();
+    placeholders.dedup();
+    placeholders.len()
+}
+
+// Checks a template message against its definition so meta doesn't reject it
+pub fn validate_template(
+    template: &TemplateMessage,
+    definitions: &[TemplateDefinition],
+) -> Result<(), ManagerError> {
+    let versions: Vec<&TemplateDefinition> = definitions
+        .iter()
+        .filter(|definition| definition.name == template.name)
+        .collect();
+
+    if versions.is_empty() {
+        return Err(ManagerError::Validation(format!(
+            "Template {} doesn't exist",
+            template.name
+        )));
+    }
+
+    let definition = match versions.iter().find(|d| d.language == template.language) {
+        Some(definition) => definition,
+        None => {
+            return Err(ManagerError::Validation(format!(
+                "Template {} is not available in {}, available languages: {}",
+                template.name,
+                template.language,
+                versions
+                    .iter()
+                    .map(|d| d.language.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            )))
+        }
+    };
+
+    if definition.status.as_deref() != Some("APPROVED") {
+        return Err(ManagerError::Validation(format!(
+            "Template {} is not approved, status: {}",
+            template.name,
+            definition.status.as_deref().unwrap_or("UNKNOWN")
+        )));
+    }
+
+    for component_type in ["header", "body"] {
+        let defined = definition
+            .components
+            .iter()
+            .find(|c| c.component_type.eq_ignore_ascii_case(component_type));
+
+        let parameters: Vec<&TemplateParameter> = template
+            .components
+            .iter()
+            .filter(|c| c.component_type == component_type)
+            .flat_map(|c| c.parameters.iter())
+            .collect();
+
+        // Media headers always take their media as parameter
+        let expected = match defined {
+            Some(component) => match component.format.as_deref() {
+                Some("IMAGE") | Some("VIDEO") | Some("DOCUMENT") => 1,
+                _ => component.text.as_deref().map_or(0, placeholder_count),
+            },
+            None => 0,
+        };
+
+        if parameters.len() != expected {
+            return Err(ManagerError::Validation(format!(
+                "Template {} {} expects {} parameters, received {}",
+                template.name,
+                component_type,
+                expected,
+                parameters.len()
+            )));
+        }
+
+        if let Some(format) = defined.and_then(|c| c.format.as_deref()) {
+            let format = format.to_lowercase();
+            if format != "text" && parameters.iter().any(|p| p.as_str() != format) {
+                return Err(ManagerError::Validation(format!(
+                    "Template {} header expects a {} parameter",
+                    template.name, format
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definitions() -> Vec<TemplateDefinition> {
+        serde_json::from_str(
+            r#"[
+                {"name": "cotizacion", "language": "es_AR", "status": "APPROVED", "category": "UTILITY", "components": [
+                    {"type": "HEADER", "format": "IMAGE"},
+                    {"type": "BODY", "text": "Hola {{1}}, tu cotización de {{2}} vence el {{3}}. Gracias {{1}}"}
+                ]},
+                {"name": "cotizacion", "language": "en_US", "status": "PENDING", "category": "UTILITY", "components": []},
+                {"name": "recordatorio", "language": "es_AR", "status": "APPROVED", "category": "UTILITY", "components": [
+                    {"type": "BODY", "text": "Tu turno es mañana"}
+                ]}
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    fn message(name: &str, language: &str, components: &str) -> TemplateMessage {
+        serde_json::from_str(&format!(
+            r#"{{"name": "{}", "language": "{}", "components": {}}}"#,
+            name, language, components
+        ))
+        .unwrap()
+    }
+
+    fn validation_message(template: &TemplateMessage) -> String {
+        match validate_template(template, &definitions()) {
+            Err(ManagerError::Validation(message)) => message,
+            Err(err) => panic!("Expected a validation error, found {:?}", err),
+            Ok(_) => panic!("Expected a validation error"),
+        }
+    }
+
+    const IMAGE_HEADER: &str = r#"{"type": "header", "parameters": [{"type": "image", "image": {"link": "https://example.com/auto.jpg"}}]}"#;
+
+    fn body(parameters: usize) -> String {
+        let parameters: Vec<String> = (0..parameters)
+            .map(|n| format!(r#"{{"type": "text", "text": "{}"}}"#, n))
+            .collect();
+
+        format!(
+            r#"{{"type": "body", "parameters": [{}]}}"#,
+            parameters.join(", ")
+        )
+    }
+
+    #[test]
+    fn counts_distinct_placeholders() {
+        assert_eq!(placeholder_count("Tu turno es mañana"), 0);
+        assert_eq!(placeholder_count("Hola {{1}}, tu auto {{2}} está listo"), 2);
+        assert_eq!(placeholder_count("Hola {{1}}, gracias {{ 1 }}"), 1);
+        assert_eq!(placeholder_count("Hola {{1}} {{2"), 1);
+    }
+
+    #[test]
+    fn accepts_template_matching_its_definition() {
+        let template = message(
+            "cotizacion",
+            "es_AR",
+            &format!("[{}, {}]", IMAGE_HEADER, body(3)),
+        );
+        assert!(validate_template(&template, &definitions()).is_ok());
+
+        let template = message("recordatorio", "es_AR", "[]");
+        assert!(validate_template(&template, &definitions()).is_ok());
+    }
+
+    #[test]
+    fn rejects_wrong_parameter_counts() {
+        let template = message(
+            "cotizacion",
+            "es_AR",
+            &format!("[{}, {}]", IMAGE_HEADER, body(2)),
+        );
+        assert_eq!(
+            validation_message(&template),
+            "Template cotizacion body expects 3 parameters, received 2"
+        );
+
+        let template = message("cotizacion", "es_AR", &format!("[{}]", body(3)));
+        assert_eq!(
+            validation_message(&template),
+            "Template cotizacion header expects 1 parameters, received 0"
+        );
+
+        let template = message("recordatorio", "es_AR", &format!("[{}]", body(1)));
+        assert_eq!(
+            validation_message(&template),
+            "Template recordatorio body expects 0 parameters, received 1"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_or_unapproved_templates() {
+        assert_eq!(
+            validation_message(&message("promo", "es_AR", "[]")),
+            "Template promo doesn't exist"
+        );
+        assert_eq!(
+            validation_message(&message("recordatorio", "pt_BR", "[]")),
+            "Template recordatorio is not available in pt_BR, available languages: es_AR"
+        );
+        assert_eq!(
+            validation_message(&message("cotizacion", "en_US", "[]")),
+            "Template cotizacion is not approved, status: PENDING"
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_header_media() {
+        let header = r#"{"type": "header", "parameters": [{"type": "video", "video": {"link": "https://example.com/auto.mp4"}}]}"#;
+        let template = message("cotizacion", "es_AR", &format!("[{}, {}]", header, body(3)));
+
+        assert_eq!(
+            validation_message(&template),
+            "Template cotizacion header expects a image parameter"
+        );
+    }
+}
@@ -0,0 +1,441 @@
+use crate::structs::webhooks::{
+    ContactAddress, ContactEmail, ContactName, ContactOrg, ContactPhone, ContactUrl, SharedContact,
+};
+use std::error::Error;
+
+// Converts a whatsapp contact to vCard 3.0 text
+pub fn to_vcard(contact: &SharedContact) -> String {
+    let mut lines = vec!["BEGIN:VCARD".to_string(), "VERSION:3.0".to_string()];
+
+    let name = &contact.name;
+    lines.push(format!(
+        "N:{}",
+        join_components(&[
+            &name.last_name,
+            &name.first_name,
+            &name.middle_name,
+            &name.prefix,
+            &name.suffix,
+        ])
+    ));
+    lines.push(format!("FN:{}", escape(&name.formatted_name)));
+
+    if let Some(org) = &contact.org {
+        if org.company.is_some() || org.department.is_some() {
+            lines.push(format!(
+                "ORG:{}",
+                join_components(&[&org.company, &org.department])
+            ));
+        }
+
+        if let Some(title) = &org.title {
+            lines.push(format!("TITLE:{}", escape(title)));
+        }
+    }
+
+    for phone in contact.phones.iter().flatten() {
+        let mut property = format!("TEL{}", type_param(&phone.phone_type));
+        if let Some(wa_id) = &phone.wa_id {
+            property.push_str(&format!(";waid={}", wa_id));
+        }
+
+        // Whatsapp only sends the wa_id for some numbers, it is the same number without format
+        let number = phone.phone.as_ref().or(phone.wa_id.as_ref());
+        if let Some(number) = number {
+            lines.push(format!("{}:{}", property, escape(number)));
+        }
+    }
+
+    for email in contact.emails.iter().flatten() {
+        if let Some(address) = &email.email {
+            lines.push(format!(
+                "EMAIL{}:{}",
+                type_param(&email.email_type),
+                escape(address)
+            ));
+        }
+    }
+
+    for address in contact.addresses.iter().flatten() {
+        lines.push(format!(
+            "ADR{}:;;{}",
+            type_param(&address.address_type),
+            join_components(&[
+                &address.street,
+                &address.city,
+                &address.state,
+                &address.zip,
+                &address.country,
+            ])
+        ));
+    }
+
+    for url in contact.urls.iter().flatten() {
+        if let Some(link) = &url.url {
+            lines.push(format!("URL{}:{}", type_param(&url.url_type), escape(link)));
+        }
+    }
+
+    if let Some(birthday) = &contact.birthday {
+        lines.push(format!("BDAY:{}", escape(birthday)));
+    }
+
+    lines.push("END:VCARD".to_string());
+
+    lines.join("\r\n") + "\r\n"
+}
+
+// Parses the first vCard of the text, only properties whatsapp supports are kept
+pub fn from_vcard(vcard: &str) -> Result<SharedContact, Box<dyn Error>> {
+    let mut contact = SharedContact {
+        name: ContactName {
+            formatted_name: "".to_string(),
+            first_name: None,
+            last_name: None,
+            middle_name: None,
+            prefix: None,
+            suffix: None,
+        },
+        phones: None,
+        emails: None,
+        addresses: None,
+        org: None,
+        urls: None,
+        birthday: None,
+    };
+
+    let mut started = false;
+    let mut ended = false;
+
+    for line in unfold(vcard) {
+        let (property, value) = match split_unescaped(&line, ':', 2).as_slice() {
+            [property, value] => (property.clone(), value.clone()),
+            _ => continue,
+        };
+
+        let mut params = property.split(';');
+        let name = params.next().unwrap_or_default().to_uppercase();
+        // Group prefixes like item1.TEL are not meaningful here
+        let name = name.rsplit('.').next().unwrap_or_default().to_string();
+        let params: Vec<&str> = params.collect();
+
+        match name.as_str() {
+            "BEGIN" if value.eq_ignore_ascii_case("VCARD") => started = true,
+            "END" if value.eq_ignore_ascii_case("VCARD") => {
+                ended = true;
+                break;
+            }
+            _ if !started => {}
+            "FN" => contact.name.formatted_name = unescape(&value),
+            "N" => {
+                let mut components = components(&value, 5).into_iter();
+                contact.name.last_name = components.next().flatten();
+                contact.name.first_name = components.next().flatten();
+                contact.name.middle_name = components.next().flatten();
+                contact.name.prefix = components.next().flatten();
+                contact.name.suffix = components.next().flatten();
+            }
+            "ORG" => {
+                let mut components = components(&value, 2).into_iter();
+                let org = contact.org.get_or_insert(ContactOrg {
+                    company: None,
+                    department: None,
+                    title: None,
+                });
+                org.company = components.next().flatten();
+                org.department = components.next().flatten();
+            }
+            "TITLE" => {
+                contact
+                    .org
+                    .get_or_insert(ContactOrg {
+                        company: None,
+                        department: None,
+                        title: None,
+                    })
+                    .title = Some(unescape(&value));
+            }
+            "TEL" => contact.phones.get_or_insert(vec![]).push(ContactPhone {
+                phone: Some(unescape(&value)),
+                phone_type: param_type(&params),
+                wa_id: param_value(&params, "WAID"),
+            }),
+            "EMAIL" => contact.emails.get_or_insert(vec![]).push(ContactEmail {
+                email: Some(unescape(&value)),
+                email_type: param_type(&params),
+            }),
+            "ADR" => {
+                // Post office box and extended address are not supported by whatsapp
+                let mut components = components(&value, 7).into_iter().skip(2);
+                contact
+                    .addresses
+                    .get_or_insert(vec![])
+                    .push(ContactAddress {
+                        street: components.next().flatten(),
+                        city: components.next().flatten(),
+                        state: components.next().flatten(),
+                        zip: components.next().flatten(),
+                        country: components.next().flatten(),
+                        country_code: None,
+                        address_type: param_type(&params),
+                    });
+            }
+            "URL" => contact.urls.get_or_insert(vec![]).push(ContactUrl {
+                url: Some(unescape(&value)),
+                url_type: param_type(&params),
+            }),
+            "BDAY" => contact.birthday = Some(unescape(&value)),
+            _ => {}
+        }
+    }
+
+    if !started || !ended {
+        return Err("Text is not a vCard".into());
+    }
+
+    if contact.name.formatted_name.is_empty() {
+        return Err("vCard has no formatted name (FN)".into());
+    }
+
+    Ok(contact)
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace(',', "\\,")
+        .replace(';', "\\;")
+}
+
+fn unescape(value: &str) -> String {
+    let mut result = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') | Some('N') => result.push('\n'),
+            Some(other) => result.push(other),
+            None => {}
+        }
+    }
+
+    result
+}
+
+fn join_components(components: &[&Option<String>]) -> String {
+    components
+        .iter()
+        .map(|component| component.as_deref().map(escape).unwrap_or_default())
+        .collect::<Vec<String>>()
+        .join(";")
+}
+
+// Splits a structured value, empty components are returned as None
+fn components(value: &str, count: usize) -> Vec<Option<String>> {
+    let mut components: Vec<Option<String>> = split_unescaped(value, ';', count)
+        .iter()
+        .map(|component| Some(unescape(component)).filter(|c| !c.is_empty()))
+        .collect();
+
+    components.resize(count, None);
+    components
+}
+
+fn split_unescaped(value: &str, separator: char, max: usize) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut escaped = false;
+
+    for c in value.chars() {
+        if c == separator && !escaped && parts.len() < max {
+            parts.push(String::new());
+            continue;
+        }
+
+        escaped = c == '\\' && !escaped;
+        parts.last_mut().unwrap().push(c);
+    }
+
+    parts
+}
+
+// Long lines are folded on a new line starting with a space or tab
+fn unfold(vcard: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+
+    for line in vcard.lines() {
+        match (
+            line.strip_prefix(' ').or(line.strip_prefix('\t')),
+            lines.last_mut(),
+        ) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ => lines.push(line.to_string()),
+        }
+    }
+
+    lines
+}
+
+fn type_param(value: &Option<String>) -> String {
+    match value {
+        Some(value) if !value.is_empty() => format!(";TYPE={}", value.to_uppercase()),
+        _ => "".to_string(),
+    }
+}
+
+// vCard 2.1 style bare parameters, like TEL;CELL, are also types
+fn param_type(params: &[&str]) -> Option<String> {
+    param_value(params, "TYPE").or(params
+        .iter()
+        .find(|param| !param.contains('='))
+        .map(|param| param.to_uppercase()))
+}
+
+fn param_value(params: &[&str], name: &str) -> Option<String> {
+    params.iter().find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if key.eq_ignore_ascii_case(name) {
+            Some(value.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(formatted_name: &str) -> ContactName {
+        ContactName {
+            formatted_name: formatted_name.to_string(),
+            first_name: None,
+            last_name: None,
+            middle_name: None,
+            prefix: None,
+            suffix: None,
+        }
+    }
+
+    fn contact(name: ContactName) -> SharedContact {
+        SharedContact {
+            name,
+            phones: None,
+            emails: None,
+            addresses: None,
+            org: None,
+            urls: None,
+            birthday: None,
+        }
+    }
+
+    fn assert_same(a: &SharedContact, b: &SharedContact) {
+        assert_eq!(
+            serde_json::to_value(a).unwrap(),
+            serde_json::to_value(b).unwrap()
+        );
+    }
+
+    #[test]
+    fn round_trips_every_property() {
+        let mut full = contact(ContactName {
+            formatted_name: "Dr. Juan Andres Perez Jr.".to_string(),
+            first_name: Some("Juan".to_string()),
+            last_name: Some("Perez".to_string()),
+            middle_name: Some("Andres".to_string()),
+            prefix: Some("Dr.".to_string()),
+            suffix: Some("Jr.".to_string()),
+        });
+        full.phones = Some(vec![ContactPhone {
+            phone: Some("+56 9 1234 5678".to_string()),
+            phone_type: Some("WORK".to_string()),
+            wa_id: Some("56912345678".to_string()),
+        }]);
+        full.emails = Some(vec![ContactEmail {
+            email: Some("juan@example.com".to_string()),
+            email_type: Some("WORK".to_string()),
+        }]);
+        full.addresses = Some(vec![ContactAddress {
+            street: Some("Av. Libertador 1234".to_string()),
+            city: Some("Santiago".to_string()),
+            state: Some("RM".to_string()),
+            zip: Some("8320000".to_string()),
+            country: Some("Chile".to_string()),
+            country_code: None,
+            address_type: Some("WORK".to_string()),
+        }]);
+        full.org = Some(ContactOrg {
+            company: Some("Pescara Auto".to_string()),
+            department: Some("Ventas".to_string()),
+            title: Some("Vendedor".to_string()),
+        });
+        full.urls = Some(vec![ContactUrl {
+            url: Some("https://example.com".to_string()),
+            url_type: Some("WORK".to_string()),
+        }]);
+        full.birthday = Some("1990-01-31".to_string());
+
+        assert_same(&from_vcard(&to_vcard(&full)).unwrap(), &full);
+    }
+
+    #[test]
+    fn escapes_separators_and_newlines() {
+        let mut escaped = contact(name("Perez, Juan; el de\nventas"));
+        escaped.name.last_name = Some("Perez; Soto".to_string());
+
+        let vcard = to_vcard(&escaped);
+
+        assert!(vcard.contains("FN:Perez\\, Juan\\; el de\\nventas\r\n"));
+        assert!(vcard.contains("N:Perez\\; Soto;;;;\r\n"));
+        assert_same(&from_vcard(&vcard).unwrap(), &escaped);
+    }
+
+    #[test]
+    fn unfolds_long_lines() {
+        let vcard = "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Pedro\r\n  Soto\r\nN:Soto;Pe\r\n\tdro;;;\r\nEND:VCARD\r\n";
+
+        let parsed = from_vcard(vcard).unwrap();
+
+        assert_eq!(parsed.name.formatted_name, "Pedro Soto");
+        assert_eq!(parsed.name.first_name.as_deref(), Some("Pedro"));
+    }
+
+    #[test]
+    fn keeps_every_phone_and_email() {
+        let vcard = "BEGIN:VCARD\nVERSION:2.1\nFN:Pedro Soto\nTEL;CELL:+56 9 8765 4321\n\
+            item1.TEL;TYPE=home;waid=56912345678:+56 9 1234 5678\n\
+            EMAIL;TYPE=WORK:pedro@example.com\nEMAIL:pedro@gmail.com\nEND:VCARD";
+
+        let parsed = from_vcard(vcard).unwrap();
+        let phones = parsed.phones.unwrap();
+        let emails = parsed.emails.unwrap();
+
+        assert_eq!(phones.len(), 2);
+        assert_eq!(phones[0].phone_type.as_deref(), Some("CELL"));
+        assert_eq!(phones[1].phone.as_deref(), Some("+56 9 1234 5678"));
+        assert_eq!(phones[1].phone_type.as_deref(), Some("home"));
+        assert_eq!(phones[1].wa_id.as_deref(), Some("56912345678"));
+        assert_eq!(emails.len(), 2);
+        assert_eq!(emails[0].email_type.as_deref(), Some("WORK"));
+        assert_eq!(emails[1].email.as_deref(), Some("pedro@gmail.com"));
+        assert_eq!(emails[1].email_type, None);
+    }
+
+    #[test]
+    fn rejects_missing_or_invalid_vcards() {
+        for vcard in [
+            "",
+            "Pedro Soto",
+            "BEGIN:VCARD\r\nFN:Pedro Soto\r\n",
+            "FN:Pedro Soto\r\nEND:VCARD\r\n",
+            "BEGIN:VCARD\r\nN:Soto;Pedro;;;\r\nEND:VCARD\r\n",
+        ] {
+            assert!(from_vcard(vcard).is_err(), "{:?}", vcard);
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use hmac::{Hmac, Mac};
+use serde_derive::Deserialize;
+use sha2::Sha256;
+use std::fmt;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+    Missing,
+    Malformed,
+    Mismatch,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "{} header is missing", SIGNATURE_HEADER),
+            SignatureError::Malformed => write!(f, "{} header is malformed", SIGNATURE_HEADER),
+            SignatureError::Mismatch => write!(f, "Webhook signature doesn't match the payload"),
+        }
+    }
+}
+
+// Meta signs the raw body with the app secret, the header has the form sha256=<hex digest>
+pub fn verify_signature(
+    body: &[u8],
+    signature: Option<&str>,
+    app_secret: &str,
+) -> Result<(), SignatureError> {
+    let signature = signature.ok_or(SignatureError::Missing)?;
+
+    let digest = signature
+        .strip_prefix("sha256=")
+        .and_then(|digest| hex::decode(digest).ok())
+        .filter(|digest| !digest.is_empty())
+        .ok_or(SignatureError::Malformed)?;
+
+    let mut mac = HmacSha256::new_from_slice(app_secret.as_bytes()).unwrap();
+    mac.update(body);
+
+    mac.verify_slice(&digest)
+        .map_err(|_| SignatureError::Mismatch)
+}
+
+// Query sent by meta on GET /webhook when the webhook is subscribed
+#[derive(Deserialize)]
+pub struct VerificationQuery {
+    #[serde(rename = "hub.mode")]
+    pub mode: String,
+    #[serde(rename = "hub.verify_token")]
+    pub verify_token: String,
+    #[serde(rename = "hub.challenge")]
+    pub challenge: String,
+}
+
+// The verify token is a secret, it is never written to the logs
+impl fmt::Debug for VerificationQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VerificationQuery")
+            .field("mode", &self.mode)
+            .field("verify_token", &"<redacted>")
+            .field("challenge", &self.challenge)
+            .finish()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SubscriptionError {
+    InvalidMode,
+    TokenMismatch,
+}
+
+impl fmt::Display for SubscriptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubscriptionError::InvalidMode => write!(f, "hub.mode must be subscribe"),
+            SubscriptionError::TokenMismatch => {
+                write!(
+                    f,
+                    "Received verification token is not equals to defined one"
+                )
+            }
+        }
+    }
+}
+
+// Returns the challenge meta expects back when the subscription is valid
+pub fn verify_subscription(
+    query: &VerificationQuery,
+    verify_token: &str,
+) -> Result<String, SubscriptionError> {
+    if query.mode != "subscribe" {
+        return Err(SubscriptionError::InvalidMode);
+    }
+
+    if query.verify_token != verify_token {
+        return Err(SubscriptionError::TokenMismatch);
+    }
+
+    Ok(query.challenge.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::webhooks::Event;
+
+    const APP_SECRET: &str = "test-app-secret";
+
+    const TEXT_MESSAGE: &[u8] = include_bytes!("../tests/fixtures/webhook_text_message.json");
+    const TEXT_MESSAGE_SIGNATURE: &str =
+        "sha256=1cd8aae2de23a7f9bacb6028c69516e2fcc0902b05fe031391da8294f28d236c";
+
+    const STATUS: &[u8] = include_bytes!("../tests/fixtures/webhook_status.json");
+    const STATUS_SIGNATURE: &str =
+        "sha256=ad9b37b0060aef788e33d84b0a47177bde719d0426873e04641a8693a20adf88";
+
+    fn verification_query(mode: &str, verify_token: &str) -> VerificationQuery {
+        VerificationQuery {
+            mode: mode.to_string(),
+            verify_token: verify_token.to_string(),
+            challenge: "1158201444".to_string(),
+        }
+    }
+
+    #[test]
+    fn subscription_returns_challenge() {
+        assert_eq!(
+            verify_subscription(&verification_query("subscribe", "token"), "token"),
+            Ok("1158201444".to_string())
+        );
+    }
+
+    #[test]
+    fn subscription_rejects_other_modes() {
+        assert_eq!(
+            verify_subscription(&verification_query("unsubscribe", "token"), "token"),
+            Err(SubscriptionError::InvalidMode)
+        );
+    }
+
+    #[test]
+    fn subscription_rejects_wrong_token() {
+        assert_eq!(
+            verify_subscription(&verification_query("subscribe", "another"), "token"),
+            Err(SubscriptionError::TokenMismatch)
+        );
+    }
+
+    #[test]
+    fn verification_query_debug_hides_token() {
+        let debug = format!("{:?}", verification_query("subscribe", "secret-token"));
+
+        assert!(debug.contains("subscribe"));
+        assert!(!debug.contains("secret-token"));
+    }
+
+    #[test]
+    fn accepts_signed_fixtures() {
+        assert_eq!(
+            verify_signature(TEXT_MESSAGE, Some(TEXT_MESSAGE_SIGNATURE), APP_SECRET),
+            Ok(())
+        );
+        assert_eq!(
+            verify_signature(STATUS, Some(STATUS_SIGNATURE), APP_SECRET),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn accepts_uppercase_digest() {
+        let signature = format!("sha256={}", TEXT_MESSAGE_SIGNATURE[7..].to_uppercase());
+
+        assert_eq!(
+            verify_signature(TEXT_MESSAGE, Some(&signature), APP_SECRET),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn signed_fixtures_are_valid_events() {
+        let message: Event = serde_json::from_slice(TEXT_MESSAGE).unwrap();
+        let status: Event = serde_json::from_slice(STATUS).unwrap();
+
+        assert_eq!(message.split_messages().len(), 1);
+        assert_eq!(status.statuses().len(), 1);
+    }
+
+    #[test]
+    fn rejects_tampered_body() {
+        let tampered = String::from_utf8(TEXT_MESSAGE.to_vec())
+            .unwrap()
+            .replace("filtro de aceite", "filtro de aire");
+
+        assert_eq!(
+            verify_signature(
+                tampered.as_bytes(),
+                Some(TEXT_MESSAGE_SIGNATURE),
+                APP_SECRET
+            ),
+            Err(SignatureError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn rejects_signature_of_another_payload() {
+        assert_eq!(
+            verify_signature(TEXT_MESSAGE, Some(STATUS_SIGNATURE), APP_SECRET),
+            Err(SignatureError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_secret() {
+        assert_eq!(
+            verify_signature(TEXT_MESSAGE, Some(TEXT_MESSAGE_SIGNATURE), "another-secret"),
+            Err(SignatureError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn rejects_missing_signature() {
+        assert_eq!(
+            verify_signature(TEXT_MESSAGE, None, APP_SECRET),
+            Err(SignatureError::Missing)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_signature() {
+        for signature in [
+            "1cd8aae2de23a7f9bacb6028c69516e2fcc0902b05fe031391da8294f28d236c",
+            "sha1=1cd8aae2de23a7f9bacb6028c69516e2fcc0902b05fe031391da8294f28d236c",
+            "sha256=not-hex",
+            "sha256=",
+        ] {
+            assert_eq!(
+                verify_signature(TEXT_MESSAGE, Some(signature), APP_SECRET),
+                Err(SignatureError::Malformed),
+                "{}",
+                signature
+            );
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use crate::redis::{
+    ack_webhook_event, claim_stale_webhook_events, create_webhook_group, dead_letter_webhook_event,
+    read_webhook_events,
+};
+use crate::request_handler;
+use crate::structs::webhooks::Event;
+use crate::structs::StandardResponse;
+use log::{error, info, warn};
+use redis::streams::StreamId;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Failed events are retried until delivered this many times, then moved to the dead letter stream
+const MAX_DELIVERIES: usize = 5;
+// Time an event can stay unacknowledged before another consumer takes it
+const RECLAIM_IDLE_MS: usize = 60_000;
+const RECLAIM_INTERVAL: Duration = Duration::from_secs(30);
+const READ_COUNT: usize = 10;
+const READ_BLOCK_MS: usize = 5_000;
+// The worker is considered dead when its loop didn't run for this long
+const HEARTBEAT_TIMEOUT_SECS: u64 = 5 * 60;
+
+// Unix time of the last worker loop, 0 until the worker starts
+static HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+
+pub fn start() {
+    let consumer = std::env::var("HOSTNAME").unwrap_or("whatsapp-manager".to_string());
+
+    // The loop is restarted if it panics, otherwise events would be enqueued and never processed
+    thread::spawn(move || loop {
+        if let Err(err) = panic::catch_unwind(|| run(&consumer)) {
+            error!(
+                "Webhook worker panicked, restarting: {}",
+                panic_message(&*err)
+            );
+        }
+        thread::sleep(Duration::from_secs(5));
+    });
+}
+
+pub fn is_alive() -> bool {
+    let heartbeat = HEARTBEAT.load(Ordering::Relaxed);
+
+    heartbeat != 0 && now().saturating_sub(heartbeat) < HEARTBEAT_TIMEOUT_SECS
+}
+
+fn beat() {
+    HEARTBEAT.store(now(), Ordering::Relaxed);
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+fn run(consumer: &str) {
+    info!("Starting webhook worker {}", consumer);
+    beat();
+
+    while let Err(err) = create_webhook_group() {
+        error!("Couldnt create webhook consumer group: {}", err);
+        thread::sleep(Duration::from_secs(5));
+        beat();
+    }
+
+    let mut last_reclaim: Option<Instant> = None;
+
+    loop {
+        beat();
+
+        // Retake events from consumers that died or failed to process them
+        if last_reclaim.is_none_or(|time| time.elapsed() >= RECLAIM_INTERVAL) {
+            last_reclaim = Some(Instant::now());
+
+            match claim_stale_webhook_events(consumer, RECLAIM_IDLE_MS, READ_COUNT) {
+                Ok(events) => {
+                    for (event, times_delivered) in events {
+                        if times_delivered >= MAX_DELIVERIES {
+                            dead_letter(&event, "Max deliveries reached");
+                        } else {
+                            process(&event);
+                        }
+                    }
+                }
+                Err(err) => error!("Couldnt claim stale webhook events: {}", err),
+            }
+        }
+
+        match read_webhook_events(consumer, READ_COUNT, READ_BLOCK_MS) {
+            Ok(events) => {
+                for event in events {
+                    process(&event);
+                }
+            }
+            Err(err) => {
+                error!("Couldnt read webhook events: {}", err);
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+// Failed events are left pending so they are retried once reclaimed
+fn process(stream_event: &StreamId) {
+    let body: Option<String> = stream_event.get("event");
+
+    let event: Event = match body.as_deref().map(serde_json::from_str) {
+        Some(Ok(event)) => event,
+        Some(Err(err)) => return dead_letter(stream_event, &format!("Invalid event: {}", err)),
+        None => return dead_letter(stream_event, "Missing event field"),
+    };
+
+    // A panic is not retried, it would happen again on every delivery
+    let result = panic::catch_unwind(AssertUnwindSafe(|| request_handler::webhook_message(event)));
+    beat();
+
+    match result {
+        Err(err) => dead_letter(stream_event, &format!("Panicked: {}", panic_message(&*err))),
+        Ok(Ok(_)) => {
+            if let Err(err) = ack_webhook_event(&stream_event.id) {
+                error!(
+                    "Couldnt acknowledge webhook event {}: {}",
+                    stream_event.id, err
+                );
+            }
+        }
+        // Retrying can't fix a validation error, like a message to an unregistered business number
+        Ok(Err(response)) if is_permanent(&response) => dead_letter(
+            stream_event,
+            &format!(
+                "Invalid event: {}",
+                serde_json::to_string(&response).unwrap()
+            ),
+        ),
+        Ok(Err(response)) => warn!(
+            "Webhook event {} processed with errors, it will be retried: {}",
+            stream_event.id,
+            serde_json::to_string(&response).unwrap()
+        ),
+    }
+}
+
+fn is_permanent(response: &StandardResponse) -> bool {
+    let errors = response.errors.as_deref().unwrap_or_default();
+
+    !errors.is_empty() && errors.iter().all(|err| err.code == "VALIDATION_ERROR")
+}
+
+fn dead_letter(stream_event: &StreamId, reason: &str) {
+    error!(
+        "Moving webhook event {} to dead letter: {}",
+        stream_event.id, reason
+    );
+
+    if let Err(err) = dead_letter_webhook_event(stream_event, reason) {
+        error!(
+            "Couldnt dead letter webhook event {}: {}",
+            stream_event.id, err
+        );
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or("unknown panic".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error_manager::ManagerError;
+
+    fn response(errors: Vec<ManagerError>) -> StandardResponse {
+        let mut response = StandardResponse::new();
+        response.errors = Some(errors.into_iter().map(Into::into).collect());
+        response
+    }
+
+    #[test]
+    fn only_validation_errors_are_permanent() {
+        let validation =
+            || ManagerError::Validation("Business number is not registered".to_string());
+        let internal = || ManagerError::Internal("Message is being processed".to_string());
+
+        assert!(is_permanent(&response(vec![validation()])));
+        assert!(is_permanent(&response(vec![validation(), validation()])));
+        assert!(!is_permanent(&response(vec![validation(), internal()])));
+        assert!(!is_permanent(&response(vec![internal()])));
+        assert!(!is_permanent(&response(vec![])));
+    }
+}